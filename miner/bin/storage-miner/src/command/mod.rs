@@ -96,7 +96,14 @@ impl StorageMiner {
                 r.run(repo)?;
             }
             Command::Init(init) => init.run(self.repo_path.clone().into(), self.db_config())?,
-            Command::Sectors(sectors) => sectors.run()?,
+            Command::Sectors(sectors) => {
+                let repo = FsRepo::open(
+                    self.repo_path.clone().into(),
+                    RepoType::StorageMiner,
+                    self.db_config(),
+                )?;
+                sectors.run(repo)?;
+            }
             Command::Info => crate::command::info::run(),
         }
 
@@ -1,5 +1,13 @@
-use anyhow::Result;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use repo::FsRepo;
+use rust_statemachine::{
+    force_state, request_transition, DsSectorStore, LogObserver, SectorState, SectorStore,
+    TransitionObservers,
+};
 use structopt::StructOpt;
+use utils::consts::SECTORS_SPACE;
 
 #[derive(StructOpt, Debug)]
 pub enum Sectors {
@@ -9,6 +17,8 @@ pub enum Sectors {
     Refs,
     /// Get the seal status of a sector by its ID
     Status {
+        /// Number of the sector
+        id: u64,
         /// display event log
         #[structopt(long)]
         log: bool,
@@ -18,13 +28,151 @@ pub enum Sectors {
         /// ADVANCED: manually update the state of a sector, this may aid in error recovery
         #[structopt(long)]
         really_do_it: bool,
+        /// Number of the sector
+        id: u64,
+        /// The new state, e.g. `PreCommitting`
+        state: SectorState,
+        /// Why the state is updated, kept in the sector event log
+        #[structopt(long, default_value = "manual state update")]
+        reason: String,
+    },
+    /// Move a failed sector to `Removing`, its files and its saved info are
+    /// dropped when its state machine is resumed
+    Remove {
+        /// ADVANCED: the data of the sector is lost
+        #[structopt(long)]
+        really_do_it: bool,
+        /// Number of the sector
+        id: u64,
+    },
+    /// Move a proving sector to `Terminating`, it's terminated on chain and
+    /// removed when its state machine is resumed
+    Terminate {
+        /// ADVANCED: the sector is no longer proven, and its pledge is lost
+        #[structopt(long)]
+        really_do_it: bool,
+        /// Number of the sector
+        id: u64,
     },
     /// Store random data in a sector
     PledgeSector,
 }
 
 impl Sectors {
-    pub fn run(&self) -> Result<()> {
-        todo!("Implement sectors subcommand");
+    pub fn run(&self, repo: FsRepo) -> Result<()> {
+        match self {
+            Sectors::List => {
+                let locked_repo = repo.lock()?;
+                let store = DsSectorStore::new(locked_repo.datastore(SECTORS_SPACE)?)?;
+                for info in store.list()? {
+                    // marked by the miner when it's over the budget of its state
+                    let stuck = if info.stuck { " (stuck)" } else { "" };
+                    println!("{}: {:?}{}", info.sector_id, info.state, stuck);
+                }
+                Ok(())
+            }
+            Sectors::Status { id, log } => {
+                let locked_repo = repo.lock()?;
+                let store = DsSectorStore::new(locked_repo.datastore(SECTORS_SPACE)?)?;
+                let info = match store.load(*id)? {
+                    Some(info) => info,
+                    None => bail!("sector {} not found", id),
+                };
+                println!("SectorID:\t{}", info.sector_id);
+                println!("Status:\t\t{:?}", info.state);
+                println!("Stuck:\t\t{}", info.stuck);
+                println!("Pieces:\t\t{}", info.pieces.len());
+                println!("CommD:\t\t{}", hex(&info.commd));
+                println!("CommR:\t\t{}", hex(&info.commr));
+                println!("Ticket:\t\t{} at {}", hex(&info.ticket), info.ticket_epoch);
+                println!("PreCommitMsg:\t{}", info.pre_commit_msg);
+                println!("Seed:\t\t{} at {}", hex(&info.seed), info.seed_epoch);
+                println!("CommitMsg:\t{}", info.commit_msg);
+                println!("Proof:\t\t{}", hex(&info.proof));
+                for (state, retries) in &info.retries {
+                    println!("Retries:\t{} from {:?}", retries, state);
+                }
+                if *log {
+                    println!("--------\nEvent Log:");
+                    for (i, entry) in store.log(*id)?.iter().enumerate() {
+                        println!("{}.\t{}", i, entry);
+                    }
+                }
+                Ok(())
+            }
+            Sectors::UpdateState {
+                really_do_it,
+                id,
+                state,
+                reason,
+            } => {
+                if !really_do_it {
+                    bail!("pass --really-do-it to actually execute this action");
+                }
+                // the miner holds the repo lock, so sectors are only updated
+                // while it's stopped. Only the saved state is changed, it's
+                // acted on once the sector state machine is resumed from it
+                let locked_repo = repo.lock()?;
+                let store = DsSectorStore::new(locked_repo.datastore(SECTORS_SPACE)?)?;
+                force_state(&store, &log_observers(), *id, state.clone(), reason)?;
+                println!("the saved state of sector {} is set to {:?}", id, state);
+                Ok(())
+            }
+            Sectors::Remove { really_do_it, id } => {
+                if !really_do_it {
+                    bail!("pass --really-do-it to actually execute this action");
+                }
+                let locked_repo = repo.lock()?;
+                let store = DsSectorStore::new(locked_repo.datastore(SECTORS_SPACE)?)?;
+                request_transition(
+                    &store,
+                    &log_observers(),
+                    *id,
+                    "Remove",
+                    SectorState::Removing,
+                )?;
+                // nothing is removed here, `storage-miner run` doesn't drive the
+                // sector state machines yet
+                println!(
+                    "the saved state of sector {} is set to Removing, its files are kept until its state machine is resumed",
+                    id
+                );
+                Ok(())
+            }
+            Sectors::Terminate { really_do_it, id } => {
+                if !really_do_it {
+                    bail!("pass --really-do-it to actually execute this action");
+                }
+                let locked_repo = repo.lock()?;
+                let store = DsSectorStore::new(locked_repo.datastore(SECTORS_SPACE)?)?;
+                request_transition(
+                    &store,
+                    &log_observers(),
+                    *id,
+                    "Terminate",
+                    SectorState::Terminating,
+                )?;
+                // nothing is sent here, `storage-miner run` doesn't drive the
+                // sector state machines yet
+                println!(
+                    "the saved state of sector {} is set to Terminating, no termination is sent until its state machine is resumed",
+                    id
+                );
+                Ok(())
+            }
+            _ => todo!("Implement sectors subcommand"),
+        }
     }
 }
+
+/// Log the transitions made offline, the miner is not running to observe
+/// them.
+fn log_observers() -> TransitionObservers {
+    let observers = TransitionObservers::default();
+    observers.add(Arc::new(LogObserver));
+    observers
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -2,12 +2,16 @@ pub const METADATA_SPACE: &'static str = "/metadata";
 pub const BLOCK_SPACE: &'static str = "/block";
 pub const STAGING_SPACE: &'static str = "/staging";
 pub const SECTORBUILDER_SPACE: &'static str = "/sectorbuilder";
+pub const EVENTS_SPACE: &'static str = "/events";
+pub const SECTORS_SPACE: &'static str = "/sectors";
 
-pub const ALL_NAMESPACE: [&'static str; 4] = [
+pub const ALL_NAMESPACE: [&'static str; 6] = [
     METADATA_SPACE,
     BLOCK_SPACE,
     STAGING_SPACE,
     SECTORBUILDER_SPACE,
+    EVENTS_SPACE,
+    SECTORS_SPACE,
 ];
 
 pub const SECTOR_SIZES: [usize; 1] = [32 << 30];
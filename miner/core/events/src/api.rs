@@ -0,0 +1,58 @@
+use cid::Cid;
+use futures::stream::{BoxStream, StreamExt};
+
+use plum_api_client::{ChainApi, HeadChange, StateApi};
+use plum_message::UnsignedMessage;
+use plum_tipset::{Tipset, TipsetKey};
+use plum_vm::MessageReceipt;
+
+use crate::error::*;
+
+/// The part of full node api used by `Events`. It's implemented for every
+/// `ChainApi + StateApi` client, and could be implemented by an in-memory
+/// chain for testing.
+#[async_trait::async_trait]
+pub trait EventsApi: Send + Sync {
+    /// The first item must be a single `Current` head change.
+    async fn chain_notify(&self) -> Result<BoxStream<'static, Vec<HeadChange>>>;
+    async fn chain_get_tipset(&self, key: &TipsetKey) -> Result<Tipset>;
+    /// Get the tipset at `height` on the chain of `key` (or the head if `key`
+    /// is empty), or the nearest one before it if `height` is a null round.
+    async fn chain_get_tipset_by_height(&self, height: u64, key: &TipsetKey) -> Result<Tipset>;
+    /// All (bls and secp) messages included in the block.
+    async fn chain_get_block_messages(&self, block: &Cid) -> Result<Vec<UnsignedMessage>>;
+    async fn state_get_receipt(&self, msg: &Cid, key: &TipsetKey) -> Result<MessageReceipt>;
+}
+
+#[async_trait::async_trait]
+impl<T: ChainApi + StateApi + Send + Sync> EventsApi for T {
+    async fn chain_notify(&self) -> Result<BoxStream<'static, Vec<HeadChange>>> {
+        let (_subscription_id, notify) = ChainApi::chain_notify(self).await.map_err(other)?;
+        Ok(notify.boxed())
+    }
+
+    async fn chain_get_tipset(&self, key: &TipsetKey) -> Result<Tipset> {
+        ChainApi::chain_get_tipset(self, key).await.map_err(other)
+    }
+
+    async fn chain_get_tipset_by_height(&self, height: u64, key: &TipsetKey) -> Result<Tipset> {
+        ChainApi::chain_get_tipset_by_height(self, height, key)
+            .await
+            .map_err(other)
+    }
+
+    async fn chain_get_block_messages(&self, block: &Cid) -> Result<Vec<UnsignedMessage>> {
+        let block_msgs = ChainApi::chain_get_block_messages(self, block)
+            .await
+            .map_err(other)?;
+        let mut msgs = block_msgs.bls_messages;
+        msgs.extend(block_msgs.secpk_messages.into_iter().map(|m| m.message));
+        Ok(msgs)
+    }
+
+    async fn state_get_receipt(&self, msg: &Cid, key: &TipsetKey) -> Result<MessageReceipt> {
+        StateApi::state_get_receipt(self, msg, key)
+            .await
+            .map_err(other)
+    }
+}
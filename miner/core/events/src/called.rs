@@ -0,0 +1,464 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use cid::Cid;
+use plum_message::UnsignedMessage;
+use plum_tipset::{Tipset, TipsetKey};
+use plum_vm::MessageReceipt;
+
+use crate::error::*;
+use crate::state::{StateHandler, StateMatch};
+use crate::tscache::TipSetCache;
+use crate::{RevertHandler, TriggerHeight, TriggerId, TARGET};
+
+use log::error;
+
+/// Used as `timeout` in `Events::called` when the trigger should never time out.
+pub const NO_TIMEOUT: u64 = std::u64::MAX;
+
+/// Called once when the trigger is registered, return `(done, more)`.
+/// `done` means the condition is already satisfied, so no timeout is needed,
+/// `more` means the handler still wants to receive messages.
+pub type CheckFunc = Box<dyn Fn(&Tipset) -> Result<(bool, bool)>>;
+/// Called when a matched message reached the confidence, or with `None`
+/// message and receipt when the trigger timed out. Return `true` to keep
+/// receiving messages.
+pub type CalledHandler =
+    Box<dyn Fn(Option<&UnsignedMessage>, Option<&MessageReceipt>, &Tipset, u64) -> Result<bool>>;
+/// Decide whether a message on chain is the one this trigger is waiting for.
+pub type MsgMatchFunc = Box<dyn Fn(&UnsignedMessage) -> Result<bool>>;
+
+/// Get all messages included in the tipset with the given key.
+pub(crate) type MessagesFunc = Box<dyn Fn(&TipsetKey) -> Result<Vec<UnsignedMessage>>>;
+/// Get the receipt of the message executed in the tipset with the given key.
+pub(crate) type ReceiptFunc = Box<dyn Fn(&Cid, &TipsetKey) -> Result<MessageReceipt>>;
+
+/// Height at which the message was applied (executed).
+pub type AppliedHeight = u64;
+pub type TimeoutHeight = u64;
+
+/// What a trigger is waiting for.
+pub(crate) enum TriggerKind {
+    /// A message matched by `matcher` is applied.
+    Msg {
+        matcher: MsgMatchFunc,
+        handle: CalledHandler,
+    },
+    /// `matcher` found a state change between two consecutive tipsets.
+    State {
+        matcher: StateMatch,
+        handle: StateHandler,
+    },
+}
+
+struct CalledHandlerObj {
+    confidence: u64,
+    timeout: TimeoutHeight,
+    disabled: bool,
+
+    kind: TriggerKind,
+    revert: RevertHandler,
+}
+
+impl CalledHandlerObj {
+    /// Call the handler for a queued event, `ts` is the tipset the event
+    /// happened in.
+    fn call(&self, data: &EventData, receipt: &ReceiptFunc, ts: &Tipset, h: u64) -> Result<bool> {
+        match (&self.kind, data) {
+            (TriggerKind::Msg { handle, .. }, EventData::Msg(msg)) => {
+                let rec = receipt(&msg.cid(), ts.key())?;
+                handle(Some(msg), Some(&rec), ts, h)
+            }
+            (TriggerKind::State { handle, .. }, EventData::State { prev, change }) => {
+                handle(Some(prev), ts, Some(change.as_ref()), h)
+            }
+            _ => unreachable!("event data always matches the trigger kind"),
+        }
+    }
+
+    fn call_timeout(&self, ts: &Tipset, h: u64) -> Result<bool> {
+        match &self.kind {
+            TriggerKind::Msg { handle, .. } => handle(None, None, ts, h),
+            TriggerKind::State { handle, .. } => handle(None, ts, None, h),
+        }
+    }
+}
+
+enum EventData {
+    Msg(UnsignedMessage),
+    State { prev: Tipset, change: Box<dyn Any> },
+}
+
+struct QueuedEvent {
+    trigger: TriggerId,
+    data: EventData,
+    called: bool,
+}
+
+pub(crate) struct EventsCalled {
+    triggers: BTreeMap<TriggerId, CalledHandlerObj>,
+    // trigger height -> applied height -> matched messages
+    conf_queue: BTreeMap<TriggerHeight, BTreeMap<AppliedHeight, Vec<QueuedEvent>>>,
+    // applied height -> trigger heights, used to find events to revert
+    revert_queue: BTreeMap<AppliedHeight, Vec<TriggerHeight>>,
+    // timeout height (timeout + confidence) -> trigger id -> times called
+    timeouts: BTreeMap<TimeoutHeight, BTreeMap<TriggerId, usize>>,
+
+    messages: MessagesFunc,
+    // messages fetched ahead by the cids of the tipset including them
+    prefetched: HashMap<Vec<Cid>, Vec<UnsignedMessage>>,
+    receipt: ReceiptFunc,
+}
+
+impl EventsCalled {
+    pub fn new(messages: MessagesFunc, receipt: ReceiptFunc) -> Self {
+        EventsCalled {
+            triggers: Default::default(),
+            conf_queue: Default::default(),
+            revert_queue: Default::default(),
+            timeouts: Default::default(),
+            messages,
+            prefetched: Default::default(),
+            receipt,
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        id: TriggerId,
+        kind: TriggerKind,
+        rev: RevertHandler,
+        confidence: u64,
+        timeout: TimeoutHeight,
+        more: bool,
+    ) {
+        let timeout = if timeout == NO_TIMEOUT {
+            NO_TIMEOUT
+        } else {
+            timeout + confidence
+        };
+        self.triggers.insert(
+            id,
+            CalledHandlerObj {
+                confidence,
+                timeout,
+                disabled: !more,
+                kind,
+                revert: rev,
+            },
+        );
+        if timeout != NO_TIMEOUT {
+            self.timeouts
+                .entry(timeout)
+                .or_insert(Default::default())
+                .insert(id, 0);
+        }
+    }
+
+    pub fn remove(&mut self, id: TriggerId) -> bool {
+        let trigger = match self.triggers.remove(&id) {
+            Some(trigger) => trigger,
+            None => return false,
+        };
+        if let Some(touts) = self.timeouts.get_mut(&trigger.timeout) {
+            touts.remove(&id);
+            if touts.is_empty() {
+                self.timeouts.remove(&trigger.timeout);
+            }
+        }
+        // queued events of this trigger are skipped and dropped by gc
+        true
+    }
+
+    /// Return `(confidence, disabled)` of the trigger if it exists.
+    pub fn trigger_state(&self, id: TriggerId) -> Option<(u64, bool)> {
+        self.triggers
+            .get(&id)
+            .map(|trigger| (trigger.confidence, trigger.disabled))
+    }
+
+    /// Whether any enabled trigger is waiting for messages.
+    pub fn wants_messages(&self) -> bool {
+        self.triggers
+            .values()
+            .any(|trigger| !trigger.disabled && matches!(trigger.kind, TriggerKind::Msg { .. }))
+    }
+
+    /// Use `prefetched` messages, by the cids of the tipset including them,
+    /// instead of fetching them when the tipsets are applied.
+    pub fn set_prefetched(&mut self, prefetched: HashMap<Vec<Cid>, Vec<UnsignedMessage>>) {
+        self.prefetched = prefetched;
+    }
+
+    /// Drop queued events and timeouts which could not be reverted any more,
+    /// and disabled triggers which have nothing left to do.
+    pub fn gc(&mut self, best_height: u64, gc_confidence: u64) {
+        if best_height < gc_confidence {
+            return;
+        }
+        let min_height = best_height - gc_confidence + 1;
+        self.conf_queue = self.conf_queue.split_off(&min_height);
+        self.revert_queue = self.revert_queue.split_off(&min_height);
+        self.timeouts = self.timeouts.split_off(&min_height);
+
+        let mut queued = HashSet::new();
+        let triggers = &self.triggers;
+        for by_orig_h in self.conf_queue.values_mut() {
+            for events in by_orig_h.values_mut() {
+                events.retain(|event| triggers.contains_key(&event.trigger));
+                queued.extend(events.iter().map(|event| event.trigger));
+            }
+        }
+        self.triggers.retain(|id, trigger| {
+            let waiting_timeout = trigger.timeout != NO_TIMEOUT && trigger.timeout >= min_height;
+            !trigger.disabled || queued.contains(id) || waiting_timeout
+        });
+    }
+
+    pub fn handle_reverts(&mut self, ts: &Tipset) {
+        let h = ts.height();
+        let reverts = match self.revert_queue.remove(&h) {
+            Some(reverts) => reverts,
+            None => return,
+        };
+        for trigger_h in reverts {
+            let to_revert = match self.conf_queue.get_mut(&trigger_h) {
+                Some(by_orig_h) => by_orig_h.remove(&h).unwrap_or_default(),
+                None => continue,
+            };
+            for event in to_revert {
+                if !event.called {
+                    continue;
+                }
+                let trigger = match self.triggers.get_mut(&event.trigger) {
+                    Some(trigger) => trigger,
+                    // cancelled
+                    None => continue,
+                };
+                // the message may be included again on the new chain, or
+                // the trigger times out
+                trigger.disabled = false;
+                if let Some(calls) = self
+                    .timeouts
+                    .get_mut(&trigger.timeout)
+                    .and_then(|touts| touts.get_mut(&event.trigger))
+                {
+                    *calls = calls.saturating_sub(1);
+                }
+                if let Err(e) = (trigger.revert)(ts) {
+                    error!(
+                        target: TARGET,
+                        "reverting chain trigger (call @H {}, called @ {}) failed: {:?}",
+                        h,
+                        trigger_h,
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn check_new_calls(&mut self, ts: &Tipset) {
+        if !self.wants_messages() {
+            return;
+        }
+        // messages included in the parent tipset are applied in this tipset
+        let msgs = match self.prefetched.remove(ts.parents().cids()) {
+            Some(msgs) => Ok(msgs),
+            None => (self.messages)(ts.parents()),
+        };
+        let msgs = match msgs {
+            Ok(msgs) => msgs,
+            Err(e) => {
+                error!(
+                    target: TARGET,
+                    "getting messages for tipset (h={}) failed: {:?}",
+                    ts.height(),
+                    e
+                );
+                return;
+            }
+        };
+
+        let mut seen = HashSet::new();
+        for msg in msgs {
+            if !seen.insert(msg.cid()) {
+                continue;
+            }
+            // every trigger waiting for the message gets it
+            let mut matched = vec![];
+            for (tid, trigger) in self.triggers.iter() {
+                let matcher = match &trigger.kind {
+                    TriggerKind::Msg { matcher, .. } if !trigger.disabled => matcher,
+                    _ => continue,
+                };
+                match matcher(&msg) {
+                    Ok(true) => matched.push(*tid),
+                    Ok(false) => {}
+                    Err(e) => error!(target: TARGET, "event matcher failed: {:?}", e),
+                }
+            }
+            for tid in matched {
+                self.queue_for_confidence(tid, EventData::Msg(msg.clone()), ts);
+            }
+        }
+    }
+
+    /// Run the state matchers over `prev` (the previous non-null tipset) and `ts`.
+    pub fn check_state_changes(&mut self, prev: &Tipset, ts: &Tipset) {
+        let mut matched = vec![];
+        for (tid, trigger) in self.triggers.iter() {
+            let matcher = match &trigger.kind {
+                TriggerKind::State { matcher, .. } if !trigger.disabled => matcher,
+                _ => continue,
+            };
+            match matcher(prev, ts) {
+                Ok(Some(change)) => matched.push((*tid, change)),
+                Ok(None) => {}
+                Err(e) => error!(
+                    target: TARGET,
+                    "state matcher (h={}) failed: {:?}",
+                    ts.height(),
+                    e
+                ),
+            }
+        }
+        for (tid, change) in matched {
+            let data = EventData::State {
+                prev: prev.clone(),
+                change,
+            };
+            self.queue_for_confidence(tid, data, ts);
+        }
+    }
+
+    fn queue_for_confidence(&mut self, tid: TriggerId, data: EventData, ts: &Tipset) {
+        let trigger = match self.triggers.get(&tid) {
+            Some(trigger) => trigger,
+            None => {
+                error!(
+                    target: TARGET,
+                    "queue event of unknown trigger {} (h={})",
+                    tid,
+                    ts.height()
+                );
+                return;
+            }
+        };
+        let applied_h = ts.height();
+        let trigger_h = applied_h + trigger.confidence;
+
+        self.conf_queue
+            .entry(trigger_h)
+            .or_insert(Default::default())
+            .entry(applied_h)
+            .or_insert(Default::default())
+            .push(QueuedEvent {
+                trigger: tid,
+                data,
+                called: false,
+            });
+        self.revert_queue
+            .entry(applied_h)
+            .or_insert(Default::default())
+            .push(trigger_h);
+    }
+
+    pub fn apply_with_confidence(&mut self, h: u64, ts_cache: &TipSetCache, ts: &Tipset) {
+        let by_orig_h = match self.conf_queue.get_mut(&h) {
+            Some(by_orig_h) => by_orig_h,
+            None => return,
+        };
+        for (orig_h, events) in by_orig_h.iter_mut() {
+            let trigger_ts = match ts_cache.get(*orig_h) {
+                Ok(Some(trigger_ts)) => trigger_ts,
+                Ok(None) => {
+                    error!(
+                        target: TARGET,
+                        "events: applied tipset (@H {}) not in cache", orig_h
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    error!(
+                        target: TARGET,
+                        "events: get applied tipset (@H {}) failed: {:?}", orig_h, e
+                    );
+                    continue;
+                }
+            };
+            for event in events.iter_mut() {
+                if event.called {
+                    continue;
+                }
+                let trigger = match self.triggers.get_mut(&event.trigger) {
+                    Some(trigger) => trigger,
+                    None => continue,
+                };
+                if trigger.disabled {
+                    continue;
+                }
+                let more = match trigger.call(&event.data, &self.receipt, &trigger_ts, h) {
+                    Ok(more) => more,
+                    Err(e) => {
+                        error!(
+                            target: TARGET,
+                            "chain trigger (call @H {}, called @ {}) failed: {:?}",
+                            orig_h,
+                            ts.height(),
+                            e
+                        );
+                        continue;
+                    }
+                };
+                event.called = true;
+                if let Some(touts) = self.timeouts.get_mut(&trigger.timeout) {
+                    *touts.entry(event.trigger).or_insert(0) += 1;
+                }
+                trigger.disabled = !more;
+            }
+        }
+    }
+
+    pub fn apply_timeouts(&mut self, h: u64, ts_cache: &TipSetCache, ts: &Tipset) {
+        let triggers = match self.timeouts.get(&h) {
+            Some(triggers) => triggers,
+            None => return,
+        };
+        for (tid, calls) in triggers.iter() {
+            if *calls > 0 {
+                continue;
+            }
+            let trigger = match self.triggers.get_mut(tid) {
+                Some(trigger) => trigger,
+                None => continue,
+            };
+            if trigger.disabled {
+                continue;
+            }
+            let timeout_ts = match ts_cache.get_non_null(h - trigger.confidence) {
+                Ok(timeout_ts) => timeout_ts,
+                Err(e) => {
+                    error!(
+                        target: TARGET,
+                        "events: get timeout tipset (@H {}) failed: {:?}",
+                        h - trigger.confidence,
+                        e
+                    );
+                    continue;
+                }
+            };
+            match trigger.call_timeout(&timeout_ts, ts.height()) {
+                // allows messages after timeout
+                Ok(more) => trigger.disabled = !more,
+                Err(e) => error!(
+                    target: TARGET,
+                    "chain trigger (timeout @ {}, called @ {}) failed: {:?}",
+                    h,
+                    ts.height(),
+                    e
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_std::future::timeout;
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::stream::StreamExt;
+
+use async_tools::task_manager::ServiceTaskExecutor;
+use plum_tipset::Tipset;
+
+use crate::error::*;
+use crate::{HeightHandler, RevertHandler, TriggerId, TARGET};
+
+use log::error;
+
+/// Async version of `HeightHandler`, the returned future is spawned onto the
+/// service executor, so it could call chain api without blocking head changes.
+pub type AsyncHeightHandler = Box<dyn Fn(Tipset, u64) -> BoxFuture<'static, Result<()>>>;
+/// Async version of `RevertHandler`.
+pub type AsyncRevertHandler = Box<dyn Fn(Tipset) -> BoxFuture<'static, Result<()>>>;
+
+/// Failure of a trigger handler, reported through `Events::handler_errors`.
+#[derive(Debug)]
+pub struct HandlerError {
+    pub trigger: TriggerId,
+    /// height of the tipset passed to the handler
+    pub height: u64,
+    /// whether the failed handler is the revert handler
+    pub revert: bool,
+    pub error: EventsError,
+}
+
+pub(crate) enum ApplyHandler {
+    Sync(HeightHandler),
+    Async(AsyncHeightHandler, Duration),
+}
+
+pub(crate) enum UndoHandler {
+    Sync(RevertHandler),
+    Async(AsyncRevertHandler, Duration),
+}
+
+type ErrorSender = Arc<Mutex<Option<mpsc::UnboundedSender<HandlerError>>>>;
+
+/// A handler future waiting in the queue of its trigger.
+struct Job {
+    height: u64,
+    revert: bool,
+    fut: BoxFuture<'static, Result<()>>,
+    dur: Duration,
+}
+
+pub(crate) struct Dispatcher {
+    executor: ServiceTaskExecutor,
+    errors: ErrorSender,
+    // trigger id -> queue of its async handler futures, the futures of one
+    // trigger run one by one, in the order of the head changes
+    queues: RefCell<HashMap<TriggerId, mpsc::UnboundedSender<Job>>>,
+}
+
+impl Dispatcher {
+    pub fn new(executor: ServiceTaskExecutor) -> Self {
+        Dispatcher {
+            executor,
+            errors: Default::default(),
+            queues: Default::default(),
+        }
+    }
+
+    pub fn handler_errors(&mut self) -> mpsc::UnboundedReceiver<HandlerError> {
+        let (tx, rx) = mpsc::unbounded();
+        *self.errors.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// Drop the queues of triggers which are gone, the queued futures still
+    /// run to the end.
+    pub fn retain<F: Fn(TriggerId) -> bool>(&self, f: F) {
+        self.queues.borrow_mut().retain(|id, _| f(*id));
+    }
+
+    /// Call the apply handler. Sync handlers are called in place and return
+    /// their result, async handlers are spawned and always return `Ok(())`,
+    /// their errors are only logged and reported.
+    pub fn apply(&self, id: TriggerId, hnd: &ApplyHandler, ts: &Tipset, h: u64) -> Result<()> {
+        match hnd {
+            ApplyHandler::Sync(hnd) => hnd(ts, h),
+            ApplyHandler::Async(hnd, dur) => {
+                let fut = hnd(ts.clone(), h);
+                self.spawn(id, ts.height(), false, fut, *dur);
+                Ok(())
+            }
+        }
+    }
+
+    /// Call the revert handler, same as `apply`.
+    pub fn revert(&self, id: TriggerId, rev: &UndoHandler, ts: &Tipset) -> Result<()> {
+        match rev {
+            UndoHandler::Sync(rev) => rev(ts),
+            UndoHandler::Async(rev, dur) => {
+                let fut = rev(ts.clone());
+                self.spawn(id, ts.height(), true, fut, *dur);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn report(&self, err: HandlerError) {
+        report(&self.errors, err);
+    }
+
+    /// Queue the future after the pending futures of the trigger, the queue
+    /// is spawned onto the executor when the trigger has none running.
+    fn spawn(
+        &self,
+        trigger: TriggerId,
+        height: u64,
+        revert: bool,
+        fut: BoxFuture<'static, Result<()>>,
+        dur: Duration,
+    ) {
+        let job = Job {
+            height,
+            revert,
+            fut,
+            dur,
+        };
+        let mut queues = self.queues.borrow_mut();
+        let job = match queues.get(&trigger) {
+            Some(queue) => match queue.unbounded_send(job) {
+                Ok(()) => return,
+                Err(e) => e.into_inner(),
+            },
+            None => job,
+        };
+        let (tx, rx) = mpsc::unbounded();
+        let _ = tx.unbounded_send(job);
+        queues.insert(trigger, tx);
+        (self.executor)(Box::pin(run_queue(trigger, rx, self.errors.clone())));
+    }
+}
+
+fn report(errors: &ErrorSender, err: HandlerError) {
+    if let Some(errors) = errors.lock().unwrap().as_ref() {
+        let _ = errors.unbounded_send(err);
+    }
+}
+
+/// Run the futures of one trigger in order, until its queue is dropped.
+async fn run_queue(
+    trigger: TriggerId,
+    mut jobs: mpsc::UnboundedReceiver<Job>,
+    errors: ErrorSender,
+) {
+    while let Some(job) = jobs.next().await {
+        let r = match timeout(job.dur, job.fut).await {
+            Ok(r) => r,
+            Err(_) => Err(EventsError::HandlerTimeout(job.dur)),
+        };
+        if let Err(e) = r {
+            error!(
+                target: TARGET,
+                "async chain trigger {} (@H {}, revert: {}) failed: {:?}",
+                trigger,
+                job.height,
+                job.revert,
+                e
+            );
+            report(
+                &errors,
+                HandlerError {
+                    trigger,
+                    height: job.height,
+                    revert: job.revert,
+                    error: e,
+                },
+            );
+        }
+    }
+}
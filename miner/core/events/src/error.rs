@@ -14,10 +14,29 @@ pub enum EventsError {
     RevertError(Tipset, Tipset),
     #[error("requested tipset not in cache|best:{0}|req:{1}")]
     NotInCache(u64, u64),
+    #[error("no tipset in cache, the first head change is not received yet")]
+    EmptyCache,
     #[error("fail to get tail from cache")]
     GetTailFailed,
-    #[error("overflow cache capacity|try times:{0}|cap:{0}")]
-    OverflowCacheCapacity(usize, usize),
+    #[error("called check error (h: {0}): {1}")]
+    CalledCheck(u64, Box<EventsError>),
+    #[error("trigger handler timed out after {0:?}")]
+    HandlerTimeout(std::time::Duration),
+    #[error("head change notification stream closed")]
+    NotifyClosed,
+    #[error("resync reverts more than {0} heights")]
+    ResyncTooDeep(u64),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("cbor error: {0}")]
+    Cbor(#[from] serde_cbor::Error),
     #[error("other error: {0}")]
     Other(#[from] Box<dyn std::error::Error + Send + Sync>),
 }
+
+/// Box an error of the node api or a datastore as `EventsError::Other`.
+pub(crate) fn other<E: std::error::Error + Send + Sync + 'static>(e: E) -> EventsError {
+    EventsError::Other(Box::new(e))
+}
@@ -1,24 +1,54 @@
+mod api;
+mod called;
+mod dispatch;
 pub mod error;
+mod persist;
+mod record;
+mod state;
+mod subscribe;
 mod tscache;
 
-use std::collections::BTreeMap;
+#[cfg(test)]
+mod test;
+
+use std::collections::{BTreeMap, HashMap};
 use std::future::Future;
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
-use plum_api_client::{ChainApi, HeadChange, HeadChangeType};
+use cid::Cid;
+use plum_api_client::{HeadChange, HeadChangeType};
+use plum_message::UnsignedMessage;
 use plum_tipset::{Tipset, TipsetKey};
+use plum_vm::MessageReceipt;
 
 use async_std::task::sleep;
+use futures::channel::mpsc;
 use futures::stream::StreamExt;
 
+use async_tools::task_manager::ServiceTaskExecutor;
+
+use crate::called::{EventsCalled, TriggerKind};
+use crate::dispatch::{ApplyHandler, Dispatcher, UndoHandler};
 use crate::error::*;
+use crate::subscribe::Subscribers;
 use crate::tscache::TipSetCache;
 
+pub use crate::api::EventsApi;
+pub use crate::called::{CalledHandler, CheckFunc, MsgMatchFunc, NO_TIMEOUT};
+pub use crate::dispatch::{AsyncHeightHandler, AsyncRevertHandler, HandlerError};
+pub use crate::persist::{EventsStore, PendingTrigger, TriggerInfo};
+pub use crate::record::{read_trace, read_trace_file, HeadChangeRecorder, TraceFormat};
+pub use crate::state::{StateChangeHandler, StateMatchFunc};
+pub use crate::subscribe::TipsetEvent;
+
 use log::{error, info, warn};
 
 const TARGET: &'static str = "events";
 
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
 pub type HeightHandler = Box<dyn Fn(&Tipset, u64) -> Result<()>>;
 pub type RevertHandler = Box<dyn Fn(&Tipset) -> Result<()>>;
 
@@ -27,11 +57,12 @@ pub type TriggerHeight = u64;
 pub type MsgHeight = u64;
 
 struct HeightHandlerObj {
+    height: MsgHeight,
     confidence: u64,
     called: bool,
 
-    handle: HeightHandler,
-    revert: RevertHandler,
+    handle: ApplyHandler,
+    revert: UndoHandler,
 }
 
 #[derive(Default)]
@@ -41,18 +72,84 @@ struct EventsHeight {
     ht_heights: BTreeMap<MsgHeight, Vec<TriggerId>>,
 }
 
+impl EventsHeight {
+    fn add(&mut self, id: TriggerId, hnd: HeightHandlerObj) {
+        // msg height
+        self.ht_heights
+            .entry(hnd.height)
+            .or_insert(Default::default())
+            .push(id);
+        // trigger height
+        self.ht_trigger_heights
+            .entry(hnd.height + hnd.confidence)
+            .or_insert(Default::default())
+            .push(id);
+        self.height_triggers.insert(id, hnd);
+    }
+
+    fn remove(&mut self, id: TriggerId) -> bool {
+        let hnd = match self.height_triggers.remove(&id) {
+            Some(hnd) => hnd,
+            None => return false,
+        };
+        remove_id(&mut self.ht_heights, hnd.height, id);
+        remove_id(
+            &mut self.ht_trigger_heights,
+            hnd.height + hnd.confidence,
+            id,
+        );
+        true
+    }
+
+    /// Drop all triggers which could not be reverted any more, i.e. the
+    /// trigger height is at least `gc_confidence` below `best_height`.
+    fn gc(&mut self, best_height: u64, gc_confidence: u64) {
+        if best_height < gc_confidence {
+            return;
+        }
+        let keep = self
+            .ht_trigger_heights
+            .split_off(&(best_height - gc_confidence + 1));
+        let expired = std::mem::replace(&mut self.ht_trigger_heights, keep);
+        for id in expired.into_iter().flat_map(|(_, ids)| ids) {
+            if let Some(hnd) = self.height_triggers.remove(&id) {
+                remove_id(&mut self.ht_heights, hnd.height, id);
+            }
+        }
+    }
+}
+
+fn remove_id(m: &mut BTreeMap<u64, Vec<TriggerId>>, h: u64, id: TriggerId) {
+    if let Some(ids) = m.get_mut(&h) {
+        ids.retain(|i| *i != id);
+        if ids.is_empty() {
+            m.remove(&h);
+        }
+    }
+}
+
 pub struct Events {
     ts_cache: TipSetCache,
     gc_confidence: u64,
     //
     ctr: TriggerId,
     events_height: EventsHeight,
-    // todo events_call
+    events_called: EventsCalled,
+    subscribers: Subscribers,
+
+    dispatcher: Dispatcher,
+    // times of restarting the head change subscription
+    reconnects: u64,
+    recorder: Option<HeadChangeRecorder>,
+
+    store: Option<Box<dyn EventsStore>>,
+    trigger_infos: BTreeMap<TriggerId, TriggerInfo>,
 }
 
 impl Events {
-    pub fn new<Api: ChainApi>(
+    pub fn new<Api: EventsApi + 'static>(
         api: Arc<Api>,
+        executor: ServiceTaskExecutor,
     ) -> (Arc<RwLock<Events>>, Box<dyn Future<Output = ()> + 'static>) {
         let confidence = 2 * plum_params::params().fork_length_threshold;
 
@@ -62,15 +159,35 @@ impl Events {
                 api_to_get_storage
                     .chain_get_tipset_by_height(height, &key)
                     .await
-                    .map_err(|e| EventsError::Other(Box::new(e)))
             })
         });
 
+        let api_to_get_messages = api.clone();
+        let messages = Box::new(move |key: &TipsetKey| -> Result<Vec<UnsignedMessage>> {
+            async_std::task::block_on(tipset_messages(api_to_get_messages.as_ref(), key))
+        });
+
+        let api_to_get_receipt = api.clone();
+        let receipt = Box::new(
+            move |msg: &Cid, key: &TipsetKey| -> Result<MessageReceipt> {
+                async_std::task::block_on(async {
+                    api_to_get_receipt.state_get_receipt(msg, key).await
+                })
+            },
+        );
+
         let e = Events {
             ts_cache: TipSetCache::new(confidence as usize, storage),
             gc_confidence: confidence,
             ctr: 0,
             events_height: Default::default(),
+            events_called: EventsCalled::new(messages, receipt),
+            subscribers: Default::default(),
+            dispatcher: Dispatcher::new(executor),
+            reconnects: 0,
+            recorder: None,
+            store: None,
+            trigger_infos: Default::default(),
         };
         let s = Arc::new(RwLock::new(e));
         let listen = Box::new(listen_head_changes(api, s.clone()));
@@ -78,21 +195,95 @@ impl Events {
     }
 
     pub fn head_change(&mut self, reverts: Vec<Tipset>, applies: Vec<Tipset>) -> Result<()> {
+        let old_best = self.best_height();
         self.head_change_at(&reverts, &applies)?;
-        // TODO head_change_called
+        self.head_change_called(&reverts, &applies)?;
+        self.head_change_subscribers(&reverts, &applies)?;
+
+        if !applies.is_empty() {
+            let best_height = self.ts_cache.best().height();
+            self.events_height.gc(best_height, self.gc_confidence);
+            self.events_called.gc(best_height, self.gc_confidence);
+            let height_triggers = &self.events_height.height_triggers;
+            self.dispatcher
+                .retain(|id| height_triggers.contains_key(&id));
+        }
+        self.persist(&reverts, &applies, old_best);
         Ok(())
     }
+
+    fn best_height(&self) -> u64 {
+        if self.ts_cache.is_empty() {
+            0
+        } else {
+            self.ts_cache.best().height()
+        }
+    }
+
+    /// Heights from the first null round before `ts` up to `ts`, in ascending order.
+    /// `ts` must be in the cache.
+    fn heights_until(&self, ts: &Tipset) -> Result<Vec<u64>> {
+        let mut heights = vec![ts.height()];
+        let mut sub_height = ts.height();
+        while sub_height > 0 {
+            sub_height -= 1;
+            if self.ts_cache.get(sub_height)?.is_some() {
+                break;
+            }
+            heights.push(sub_height);
+        }
+        heights.reverse();
+        Ok(heights)
+    }
+
+    /// The non-null tipset before `ts`, `None` for genesis. `ts` must be in the cache.
+    fn prev_tipset(&self, ts: &Tipset) -> Result<Option<Tipset>> {
+        match self.heights_until(ts)?.first() {
+            Some(h) if *h > 0 => self.ts_cache.get(h - 1),
+            _ => Ok(None),
+        }
+    }
+
+    /// Times the head change subscription has been restarted.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    /// Receive errors (including timeouts) of all trigger handlers. Only the
+    /// receiver returned by the latest call gets the errors.
+    pub fn handler_errors(&mut self) -> mpsc::UnboundedReceiver<HandlerError> {
+        self.dispatcher.handler_errors()
+    }
 }
 
-fn revert_func(h: u64, events_height: &mut EventsHeight, ts: &Tipset) -> Result<()> {
-    for tid in &events_height.ht_heights[&h] {
-        let mut handle = events_height.height_triggers.get_mut(tid).expect("");
-        let r = (handle.revert)(ts);
+fn revert_func(
+    h: u64,
+    events_height: &mut EventsHeight,
+    dispatcher: &Dispatcher,
+    ts: &Tipset,
+) -> Result<()> {
+    let tids = match events_height.ht_heights.get(&h) {
+        Some(tids) => tids,
+        None => return Ok(()),
+    };
+    for tid in tids {
+        let handle = events_height.height_triggers.get_mut(tid).expect("");
+        // only revert triggers which have been applied
+        if !handle.called {
+            continue;
+        }
+        let r = dispatcher.revert(*tid, &handle.revert, ts);
         if let Err(e) = r {
             error!(
                 target: TARGET,
                 "reverting chain trigger (@H {}): {:?}", h, e
             );
+            dispatcher.report(HandlerError {
+                trigger: *tid,
+                height: ts.height(),
+                revert: true,
+                error: e,
+            });
         }
         handle.called = false;
     }
@@ -102,93 +293,308 @@ fn revert_func(h: u64, events_height: &mut EventsHeight, ts: &Tipset) -> Result<
 fn apply_func(
     h: u64,
     events_height: &mut EventsHeight,
+    dispatcher: &Dispatcher,
     ts_cache: &TipSetCache,
     ts: &Tipset,
 ) -> Result<()> {
-    for tid in events_height.ht_trigger_heights[&h].iter() {
-        // let hnd = &mut events_height.height_triggers[tid];
+    let tids = match events_height.ht_trigger_heights.get(&h) {
+        Some(tids) => tids,
+        None => return Ok(()),
+    };
+    for tid in tids.iter() {
         let hnd = events_height.height_triggers.get_mut(tid).expect("");
         if hnd.called {
-            // TODO return???
-            return Ok(());
+            continue;
         }
         hnd.called = true;
         let trigger_height = h - hnd.confidence;
 
         let inc_tipset = ts_cache.get_non_null(trigger_height)?;
-        let r = (hnd.handle)(&inc_tipset, h);
+        let r = dispatcher.apply(*tid, &hnd.handle, &inc_tipset, h);
         if let Err(e) = r {
             error!(
+                target: TARGET,
                 "chain trigger (@H {}, called @ {}) failed: {:?}",
                 trigger_height,
                 ts.height(),
                 e
             );
+            dispatcher.report(HandlerError {
+                trigger: *tid,
+                height: inc_tipset.height(),
+                revert: false,
+                error: e,
+            });
         }
     }
     Ok(())
 }
 
+/// Events function impl for recording and replaying head changes
+impl Events {
+    /// Record all head change batches received from now on, including the
+    /// ones synthesized when resyncing after a reconnect. Replace the current
+    /// recorder if any.
+    pub fn record_head_changes(&mut self, recorder: HeadChangeRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub fn stop_recording(&mut self) -> Option<HeadChangeRecorder> {
+        self.recorder.take()
+    }
+
+    /// Feed recorded head change batches into `head_change` in order, as if
+    /// they were received from the chain. Stop at the first failed batch.
+    pub fn replay(&mut self, batches: Vec<Vec<HeadChange>>) -> Result<()> {
+        for batch in batches {
+            self.apply_head_changes(batch)?;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, changes: &[HeadChange]) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.record(changes) {
+                error!(target: TARGET, "recording head changes failed: {:?}", e);
+            }
+        }
+    }
+
+    /// Revert all cached tipsets with the triggers applied on them, and
+    /// start over from the `Current` head change `current`.
+    fn reset(&mut self, current: HeadChange) -> Result<()> {
+        let reverts = self.ts_cache.tipsets();
+        if self.recorder.is_some() {
+            self.record(&head_changes(&reverts, &[]));
+        }
+        let r = self.head_change(reverts, vec![]);
+        // reverting may stop halfway on errors
+        self.ts_cache.clear();
+        self.record(&[current.clone()]);
+        self.add_current(current.val)?;
+        r
+    }
+
+    /// Cache and save `ts` as the initial head, into an empty cache.
+    fn add_current(&mut self, ts: Tipset) -> Result<()> {
+        let height = ts.height();
+        self.ts_cache.add(ts.clone())?;
+        // nothing older is cached, nothing to drop
+        self.persist(&[], &[ts], height.saturating_sub(1));
+        Ok(())
+    }
+
+    fn apply_head_changes(&mut self, changes: Vec<HeadChange>) -> Result<()> {
+        let mut reverts = vec![];
+        let mut applies = vec![];
+        for notif in changes {
+            match notif.r#type {
+                HeadChangeType::Revert => reverts.push(notif.val),
+                HeadChangeType::Apply => applies.push(notif.val),
+                // the initial head of a trace
+                HeadChangeType::Current if self.ts_cache.is_empty() => {
+                    self.add_current(notif.val)?
+                }
+                _ => warn!(
+                    target: TARGET,
+                    "unexpected head change notification type: '{:?}'", notif.r#type
+                ),
+            }
+        }
+        self.head_change(reverts, applies)
+    }
+}
+
+/// Events function impl for persistence
+impl Events {
+    /// Restore the cache window and the pending triggers saved in `store`,
+    /// and save the state into it from now on. Must be called before
+    /// listening head changes, the returned triggers are not registered
+    /// again, the components owning them should do it (skipping the called
+    /// ones) and `describe_trigger` them again.
+    pub fn restore(&mut self, store: Box<dyn EventsStore>) -> Result<Vec<PendingTrigger>> {
+        let tipsets = persist::load_tipsets(store.as_ref(), self.ts_cache.capacity())?;
+        let triggers = persist::load_triggers(store.as_ref())?;
+        if self.ts_cache.is_empty() {
+            for ts in tipsets {
+                self.ts_cache.add(ts)?;
+            }
+        }
+        info!(
+            target: TARGET,
+            "restored events state: best height {}, {} pending triggers",
+            self.best_height(),
+            triggers.len()
+        );
+        self.store = Some(store);
+        Ok(triggers)
+    }
+
+    /// Stop saving the state, and return the store.
+    pub fn take_store(&mut self) -> Option<Box<dyn EventsStore>> {
+        self.store.take()
+    }
+
+    /// Describe what the trigger is for, so that it's saved in the pending
+    /// triggers until it's cancelled or dropped by gc.
+    pub fn describe_trigger(&mut self, id: TriggerId, info: TriggerInfo) {
+        self.trigger_infos.insert(id, info);
+        self.persist_triggers();
+    }
+
+    /// All described triggers which have not been dropped.
+    pub fn pending_triggers(&self) -> Vec<PendingTrigger> {
+        self.trigger_infos
+            .iter()
+            .filter_map(|(id, info)| {
+                let (height, confidence, called) = match self.events_height.height_triggers.get(id)
+                {
+                    Some(hnd) => (Some(hnd.height), hnd.confidence, hnd.called),
+                    None => {
+                        let (confidence, called) = self.events_called.trigger_state(*id)?;
+                        (None, confidence, called)
+                    }
+                };
+                Some(PendingTrigger {
+                    info: info.clone(),
+                    height,
+                    confidence,
+                    called,
+                })
+            })
+            .collect()
+    }
+
+    fn persist(&mut self, reverts: &[Tipset], applies: &[Tipset], old_best: u64) {
+        // drop descriptions of triggers removed by gc
+        let (events_height, events_called) = (&self.events_height, &self.events_called);
+        self.trigger_infos.retain(|id, _| {
+            events_height.height_triggers.contains_key(id)
+                || events_called.trigger_state(*id).is_some()
+        });
+
+        let store = match self.store.as_ref() {
+            Some(store) => store,
+            None => return,
+        };
+        let r = persist::save_tipsets(
+            store.as_ref(),
+            reverts,
+            applies,
+            old_best,
+            self.best_height(),
+            self.ts_cache.capacity(),
+        );
+        if let Err(e) = r {
+            error!(target: TARGET, "saving tipset cache failed: {:?}", e);
+        }
+        self.persist_triggers();
+    }
+
+    fn persist_triggers(&self) {
+        if let Some(store) = self.store.as_ref() {
+            if let Err(e) = persist::save_triggers(store.as_ref(), &self.pending_triggers()) {
+                error!(target: TARGET, "saving pending triggers failed: {:?}", e);
+            }
+        }
+    }
+}
+
 /// Events function impl for events_height
 impl Events {
+    /// Register a trigger, `hnd` is called once the tipset at height `h` got
+    /// `confidence` epochs on top of it, `rev` is called if it's reverted
+    /// after that. The returned id could be used to `cancel` the trigger,
+    /// triggers are dropped automatically once they are beyond `gc_confidence`.
+    /// Fail with `EmptyCache` before the first head change is received.
     pub fn chain_at(
         &mut self,
         hnd: HeightHandler,
         rev: RevertHandler,
         confidence: u64,
         h: u64,
-    ) -> Result<()> {
+    ) -> Result<TriggerId> {
+        self.add_height_trigger(
+            ApplyHandler::Sync(hnd),
+            UndoHandler::Sync(rev),
+            confidence,
+            h,
+        )
+    }
+
+    /// Same as `chain_at`, but the handlers are spawned onto the executor
+    /// instead of being called under the events lock. Each handler call is
+    /// bounded by `timeout`, failures are logged and sent to `handler_errors`.
+    /// The futures of one trigger run one by one, in the order of the head
+    /// changes, so a revert never overtakes the apply before it.
+    pub fn chain_at_async(
+        &mut self,
+        hnd: AsyncHeightHandler,
+        rev: AsyncRevertHandler,
+        confidence: u64,
+        h: u64,
+        timeout: Duration,
+    ) -> Result<TriggerId> {
+        self.add_height_trigger(
+            ApplyHandler::Async(hnd, timeout),
+            UndoHandler::Async(rev, timeout),
+            confidence,
+            h,
+        )
+    }
+
+    fn add_height_trigger(
+        &mut self,
+        hnd: ApplyHandler,
+        rev: UndoHandler,
+        confidence: u64,
+        h: u64,
+    ) -> Result<TriggerId> {
+        if self.ts_cache.is_empty() {
+            return Err(EventsError::EmptyCache);
+        }
+        let id = self.ctr;
+        self.ctr += 1;
+
         let best_height = self.ts_cache.best().height();
-        if best_height >= (h + confidence) {
+        let called = best_height >= (h + confidence);
+        if called {
             let ts = self.ts_cache.get_non_null(h)?;
-            (hnd)(&ts, best_height)?;
+            self.dispatcher.apply(id, &hnd, &ts, best_height)?;
             // TODO split lock
         }
-        if best_height >= h + confidence + self.gc_confidence {
-            return Ok(());
-        }
 
-        let trigger_at = h + confidence;
-        let id = self.ctr;
-        self.ctr += 1;
-
-        self.events_height.height_triggers.insert(
+        // kept even if it's already beyond `gc_confidence`, so the id stays
+        // valid until the next gc drops it
+        self.events_height.add(
             id,
             HeightHandlerObj {
+                height: h,
                 confidence,
-                called: false,
+                called,
                 handle: hnd,
                 revert: rev,
             },
         );
-        // msg height
-        self.events_height
-            .ht_heights
-            .entry(h)
-            .or_insert(Default::default())
-            .push(id);
-        // trigger height
-        self.events_height
-            .ht_trigger_heights
-            .entry(trigger_at)
-            .or_insert(Default::default())
-            .push(id);
+        Ok(id)
+    }
 
-        Ok(())
+    /// Cancel a height, message or state trigger, return false if the trigger does
+    /// not exist (or has been garbage collected).
+    pub fn cancel(&mut self, id: TriggerId) -> bool {
+        let removed = self.events_height.remove(id) || self.events_called.remove(id);
+        self.dispatcher.retain(|tid| tid != id);
+        if self.trigger_infos.remove(&id).is_some() {
+            self.persist_triggers();
+        }
+        removed
     }
 
     fn head_change_at(&mut self, reverts: &[Tipset], applies: &[Tipset]) -> Result<()> {
         for ts in reverts {
-            revert_func(ts.height(), &mut self.events_height, ts)?;
-
-            let mut sub_height = ts.height() - 1;
-            loop {
-                let cts = self.ts_cache.get(sub_height)?;
-                if cts.is_some() {
-                    break;
-                }
-                revert_func(sub_height, &mut self.events_height, ts)?;
-                sub_height -= 1;
+            // revert this height and null rounds before it
+            for h in self.heights_until(ts)?.into_iter().rev() {
+                revert_func(h, &mut self.events_height, &self.dispatcher, ts)?;
             }
             self.ts_cache.revert(ts)?;
         }
@@ -196,16 +602,14 @@ impl Events {
         for ts in applies {
             self.ts_cache.add(ts.clone())?;
             // height triggers
-            apply_func(ts.height(), &mut self.events_height, &self.ts_cache, ts)?;
-
-            let mut sub_height = ts.height() - 1;
-            loop {
-                let cts = self.ts_cache.get(sub_height)?;
-                if cts.is_some() {
-                    break;
-                }
-                apply_func(sub_height, &mut self.events_height, &self.ts_cache, ts)?;
-                sub_height -= 1;
+            for h in self.heights_until(ts)? {
+                apply_func(
+                    h,
+                    &mut self.events_height,
+                    &self.dispatcher,
+                    &self.ts_cache,
+                    ts,
+                )?;
             }
         }
         Ok(())
@@ -214,32 +618,153 @@ impl Events {
 
 /// Events function impl for events_called
 impl Events {
-    fn head_change_called(&mut self, _reverts: &[Tipset], _applies: &[Tipset]) -> Result<()> {
-        // TODO
+    /// Register a trigger for messages matched by `matcher`. `hnd` is called
+    /// once a matched message is applied and got `confidence` tipsets on top
+    /// of it, `rev` is called when such a message is reverted. If no message
+    /// matched before `timeout` height (plus confidence), `hnd` is called with
+    /// `None` message. Use `NO_TIMEOUT` to wait forever.
+    pub fn called(
+        &mut self,
+        check: CheckFunc,
+        hnd: CalledHandler,
+        rev: RevertHandler,
+        confidence: u64,
+        timeout: u64,
+        matcher: MsgMatchFunc,
+    ) -> Result<TriggerId> {
+        let kind = TriggerKind::Msg {
+            matcher,
+            handle: hnd,
+        };
+        self.add_called_trigger(check, kind, rev, confidence, timeout)
+    }
+
+    /// Register a trigger for state changes found by `matcher`, which is run
+    /// over every two consecutive (non-null) tipsets once the later one is
+    /// applied. `hnd` is called once the tipset with the change got
+    /// `confidence` tipsets on top of it, `rev` is called when such a tipset
+    /// is reverted. Timeout and `check` work the same as in `called`.
+    pub fn state_changed<T: 'static>(
+        &mut self,
+        check: CheckFunc,
+        hnd: StateChangeHandler<T>,
+        rev: RevertHandler,
+        confidence: u64,
+        timeout: u64,
+        matcher: StateMatchFunc<T>,
+    ) -> Result<TriggerId> {
+        let kind = TriggerKind::State {
+            matcher: state::erase_matcher(matcher),
+            handle: state::erase_handler(hnd),
+        };
+        self.add_called_trigger(check, kind, rev, confidence, timeout)
+    }
+
+    fn add_called_trigger(
+        &mut self,
+        check: CheckFunc,
+        kind: TriggerKind,
+        rev: RevertHandler,
+        confidence: u64,
+        timeout: u64,
+    ) -> Result<TriggerId> {
+        if self.ts_cache.is_empty() {
+            return Err(EventsError::EmptyCache);
+        }
+        let ts = self.ts_cache.best();
+        let (done, more) =
+            check(ts).map_err(|e| EventsError::CalledCheck(ts.height(), Box::new(e)))?;
+        // already done, do not need to wait for timeout
+        let timeout = if done { NO_TIMEOUT } else { timeout };
+
+        let id = self.ctr;
+        self.ctr += 1;
+        self.events_called
+            .add(id, kind, rev, confidence, timeout, more);
+        Ok(id)
+    }
+
+    fn head_change_called(&mut self, reverts: &[Tipset], applies: &[Tipset]) -> Result<()> {
+        for ts in reverts {
+            self.events_called.handle_reverts(ts);
+        }
+
+        for ts in applies {
+            // find matched messages and state changes
+            self.events_called.check_new_calls(ts);
+            if let Some(prev) = self.prev_tipset(ts)? {
+                self.events_called.check_state_changes(&prev, ts);
+            }
+
+            // apply queued events and timeouts for this height and null rounds before it
+            for h in self.heights_until(ts)? {
+                self.events_called
+                    .apply_with_confidence(h, &self.ts_cache, ts);
+                self.events_called.apply_timeouts(h, &self.ts_cache, ts);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Events function impl for subscribers
+impl Events {
+    /// Subscribe head changes filtered by `confidence`: `Apply(ts)` is sent
+    /// once `ts` got `confidence` epochs on top of it, `Revert(ts)` is sent
+    /// when such a tipset is reverted. Every tipset which gets the confidence
+    /// after subscribing is sent, including the ones applied before it, e.g.
+    /// the current head; the tipsets which already had it are not. The
+    /// subscription is dropped with the returned stream.
+    pub fn subscribe(&mut self, confidence: u64) -> mpsc::UnboundedReceiver<TipsetEvent> {
+        self.subscribers.add(confidence)
+    }
+
+    fn head_change_subscribers(&mut self, reverts: &[Tipset], applies: &[Tipset]) -> Result<()> {
+        if self.subscribers.is_empty() {
+            return Ok(());
+        }
+        for ts in reverts {
+            self.subscribers.revert(ts);
+        }
+        for ts in applies {
+            for h in self.heights_until(ts)? {
+                self.subscribers
+                    .apply(h, &self.ts_cache, self.gc_confidence)?;
+            }
+        }
         Ok(())
     }
 }
 
-async fn listen_head_changes<Api: ChainApi>(api: Arc<Api>, event: Arc<RwLock<Events>>) {
+async fn listen_head_changes<Api: EventsApi>(api: Arc<Api>, event: Arc<RwLock<Events>>) {
+    let mut backoff = RECONNECT_BACKOFF_MIN;
     loop {
         let r = listen_head_changes_once(api.clone(), event.clone()).await;
-        if let Err(msg) = r {
-            error!(target: TARGET, "listen head changes errored: {}", msg);
+        match r {
+            // the subscription worked until the stream closed, retry soon
+            Ok(()) => backoff = RECONNECT_BACKOFF_MIN,
+            Err(msg) => error!(target: TARGET, "listen head changes errored: {}", msg),
         }
-        sleep(Duration::from_secs(1)).await;
-        info!(target: TARGET, "restarting listen_head_changes");
+        let reconnects = {
+            let mut e = event.write().unwrap();
+            e.reconnects += 1;
+            e.reconnects
+        };
+        sleep(backoff).await;
+        backoff = std::cmp::min(backoff * 2, RECONNECT_BACKOFF_MAX);
+        info!(
+            target: TARGET,
+            "restarting listen_head_changes (reconnects: {})", reconnects
+        );
     }
 }
 
-async fn listen_head_changes_once<Api: ChainApi>(
+async fn listen_head_changes_once<Api: EventsApi>(
     api: Arc<Api>,
     event: Arc<RwLock<Events>>,
 ) -> Result<()> {
-    let (_subscription_id, mut notify) = api
-        .chain_notify()
-        .await
-        .map_err(|e| EventsError::Other(Box::new(e)))?;
-    let mut current: Vec<HeadChange> = notify.next().await.unwrap();
+    let mut notify = api.chain_notify().await?;
+    let mut current: Vec<HeadChange> = notify.next().await.ok_or(EventsError::NotifyClosed)?;
     if current.len() != 1 {
         return Err(EventsError::UnexpectedInitial(current.len()));
     }
@@ -248,9 +773,55 @@ async fn listen_head_changes_once<Api: ChainApi>(
         HeadChangeType::Current => {}
         _ => Err(EventsError::UnexpectedInitialType(c.r#type))?,
     }
-    let r = {
-        let mut e = event.write().unwrap();
-        e.ts_cache.add(c.val)
+
+    let best = {
+        let e = event.read().unwrap();
+        if e.ts_cache.is_empty() {
+            None
+        } else {
+            Some(e.ts_cache.best().clone())
+        }
+    };
+    let r = match best {
+        None => {
+            let mut e = event.write().unwrap();
+            e.record(&[c.clone()]);
+            e.add_current(c.val)
+        }
+        // reconnected, synthesize the head changes missed while disconnected
+        Some(best) => {
+            let max_depth = event.read().unwrap().ts_cache.capacity() as u64;
+            match chain_path(api.as_ref(), best, c.val.clone(), max_depth).await {
+                Ok((reverts, applies)) => {
+                    if !reverts.is_empty() || !applies.is_empty() {
+                        info!(
+                            target: TARGET,
+                            "resync head changes: {} reverts, {} applies",
+                            reverts.len(),
+                            applies.len()
+                        );
+                    }
+                    prefetch_messages(api.as_ref(), &event, &applies).await;
+                    let mut e = event.write().unwrap();
+                    if e.recorder.is_some() {
+                        e.record(&head_changes(&reverts, &applies));
+                    }
+                    e.head_change(reverts, applies)
+                }
+                // the cached chain could never be linked to the new head,
+                // start over from the new head instead of failing forever
+                Err(EventsError::ResyncTooDeep(depth)) => {
+                    warn!(
+                        target: TARGET,
+                        "resync reverts more than {} heights, reset to the current head (h={})",
+                        depth,
+                        c.val.height()
+                    );
+                    event.write().unwrap().reset(c)
+                }
+                Err(e) => return Err(e),
+            }
+        }
     };
     if let Err(e) = r {
         warn!(
@@ -261,25 +832,107 @@ async fn listen_head_changes_once<Api: ChainApi>(
 
     #[allow(irrefutable_let_patterns)]
     while let Some(head_change) = notify.next().await {
-        let mut reverts = vec![];
-        let mut applies = vec![];
-        for notif in head_change {
-            match notif.r#type {
-                HeadChangeType::Revert => reverts.push(notif.val),
-                HeadChangeType::Apply => applies.push(notif.val),
-                _ => warn!(
-                    target: TARGET,
-                    "unexpected head change notification type: '{:?}'", notif.r#type
-                ),
+        let applies = head_change
+            .iter()
+            .filter(|c| matches!(c.r#type, HeadChangeType::Apply))
+            .map(|c| c.val.clone())
+            .collect::<Vec<_>>();
+        prefetch_messages(api.as_ref(), &event, &applies).await;
+        let mut e = event.write().unwrap();
+        e.record(&head_change);
+        if let Err(err) = e.apply_head_changes(head_change) {
+            warn!(target: TARGET, "headChange failed: {:?}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// All messages included in the tipset with the given key.
+async fn tipset_messages<Api: EventsApi>(
+    api: &Api,
+    key: &TipsetKey,
+) -> Result<Vec<UnsignedMessage>> {
+    let mut msgs = vec![];
+    for block_cid in key.cids() {
+        msgs.extend(api.chain_get_block_messages(block_cid).await?);
+    }
+    Ok(msgs)
+}
+
+/// Fetch the messages applied in `applies` for the message triggers, so
+/// that they are not fetched while holding the lock of `event`. Nothing is
+/// fetched without enabled message triggers.
+async fn prefetch_messages<Api: EventsApi>(api: &Api, event: &RwLock<Events>, applies: &[Tipset]) {
+    let wanted = event.read().unwrap().events_called.wants_messages();
+    if !wanted || applies.is_empty() {
+        return;
+    }
+    let mut prefetched = HashMap::new();
+    for ts in applies {
+        // messages included in the parent tipset are applied in this tipset
+        match tipset_messages(api, ts.parents()).await {
+            Ok(msgs) => {
+                prefetched.insert(ts.parents().cids().to_vec(), msgs);
             }
+            // fetched again when the tipset is applied
+            Err(e) => warn!(
+                target: TARGET,
+                "prefetching messages for tipset (h={}) failed: {:?}",
+                ts.height(),
+                e
+            ),
         }
-        {
-            let mut e = event.write().unwrap();
-            if let Err(err) = e.head_change(reverts, applies) {
-                warn!(target: TARGET, "headChange failed: {:?}", err);
+    }
+    event
+        .write()
+        .unwrap()
+        .events_called
+        .set_prefetched(prefetched);
+}
+
+fn head_changes(reverts: &[Tipset], applies: &[Tipset]) -> Vec<HeadChange> {
+    let reverts = reverts.iter().map(|ts| HeadChange {
+        r#type: HeadChangeType::Revert,
+        val: ts.clone(),
+    });
+    let applies = applies.iter().map(|ts| HeadChange {
+        r#type: HeadChangeType::Apply,
+        val: ts.clone(),
+    });
+    reverts.chain(applies).collect()
+}
+
+/// Walk the chain back from both `from` and `to` to their common ancestor,
+/// return the tipsets to revert (from `from` downwards) and the tipsets to
+/// apply (upwards to `to`). Reverting more than `max_depth` heights below
+/// `from`, null rounds included, is an error.
+async fn chain_path<Api: EventsApi>(
+    api: &Api,
+    from: Tipset,
+    to: Tipset,
+    max_depth: u64,
+) -> Result<(Vec<Tipset>, Vec<Tipset>)> {
+    let mut reverts = vec![];
+    let mut applies = vec![];
+    let from_height = from.height();
+    let (mut old, mut new) = (from, to);
+    while old != new {
+        if old.height() >= new.height() {
+            if from_height - old.height() >= max_depth {
+                return Err(EventsError::ResyncTooDeep(max_depth));
             }
+            let parent = parent_tipset(api, &old).await?;
+            reverts.push(std::mem::replace(&mut old, parent));
+        } else {
+            let parent = parent_tipset(api, &new).await?;
+            applies.push(std::mem::replace(&mut new, parent));
         }
     }
+    applies.reverse();
+    Ok((reverts, applies))
+}
 
-    Ok(())
+async fn parent_tipset<Api: EventsApi>(api: &Api, ts: &Tipset) -> Result<Tipset> {
+    api.chain_get_tipset(ts.parents()).await
 }
@@ -0,0 +1,115 @@
+use datastore::{key::Key, Batching};
+use serde::{Deserialize, Serialize};
+
+use plum_tipset::Tipset;
+
+use crate::error::*;
+
+const BEST_HEIGHT_KEY: &str = "/tscache/best";
+const TRIGGERS_KEY: &str = "/triggers";
+
+fn tipset_key(height: u64) -> String {
+    format!("/tscache/{}", height)
+}
+
+/// What a trigger is for, given by the component registering it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TriggerInfo {
+    pub sector_id: Option<u64>,
+    pub purpose: String,
+}
+
+/// A described trigger which has not been dropped yet, saved so that the
+/// component could register it again after restart.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingTrigger {
+    pub info: TriggerInfo,
+    /// height of a height trigger, `None` for message and state triggers
+    pub height: Option<u64>,
+    pub confidence: u64,
+    /// the trigger fired (and was not reverted since), for message and state
+    /// triggers it means they will not fire any more
+    pub called: bool,
+}
+
+/// Where `Events` saves its state, implemented for every datastore, e.g. a
+/// `RepoDatastore` namespace.
+pub trait EventsStore {
+    fn put(&self, key: &str, value: Vec<u8>) -> Result<()>;
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    fn delete(&self, key: &str) -> Result<()>;
+}
+
+impl<DS: Batching> EventsStore for DS {
+    fn put(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        Batching::put(self, Key::new(key), value).map_err(other)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let key = Key::new(key);
+        if !self.has(&key).map_err(other)? {
+            return Ok(None);
+        }
+        Batching::get(self, &key).map(Some).map_err(other)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let key = Key::new(key);
+        if self.has(&key).map_err(other)? {
+            Batching::delete(self, &key).map_err(other)?;
+        }
+        Ok(())
+    }
+}
+
+/// Load the saved cache window in ascending height order.
+pub(crate) fn load_tipsets(store: &dyn EventsStore, capacity: usize) -> Result<Vec<Tipset>> {
+    let best_height: u64 = match store.get(BEST_HEIGHT_KEY)? {
+        Some(best) => serde_json::from_slice(&best)?,
+        None => return Ok(vec![]),
+    };
+    let tail_height = (best_height + 1).saturating_sub(capacity as u64);
+    let mut tipsets = vec![];
+    for h in tail_height..=best_height {
+        if let Some(ts) = store.get(&tipset_key(h))? {
+            tipsets.push(serde_cbor::from_slice(&ts)?);
+        }
+    }
+    Ok(tipsets)
+}
+
+/// Save the head change, and delete tipsets which dropped out of the cache
+/// window when the best height moved from `old_best` to `new_best`.
+pub(crate) fn save_tipsets(
+    store: &dyn EventsStore,
+    reverts: &[Tipset],
+    applies: &[Tipset],
+    old_best: u64,
+    new_best: u64,
+    capacity: usize,
+) -> Result<()> {
+    for ts in reverts {
+        store.delete(&tipset_key(ts.height()))?;
+    }
+    for ts in applies {
+        store.put(&tipset_key(ts.height()), serde_cbor::to_vec(ts)?)?;
+    }
+    let capacity = capacity as u64;
+    if new_best >= capacity {
+        for h in (old_best + 1).saturating_sub(capacity)..=(new_best - capacity) {
+            store.delete(&tipset_key(h))?;
+        }
+    }
+    store.put(BEST_HEIGHT_KEY, serde_json::to_vec(&new_best)?)
+}
+
+pub(crate) fn load_triggers(store: &dyn EventsStore) -> Result<Vec<PendingTrigger>> {
+    match store.get(TRIGGERS_KEY)? {
+        Some(triggers) => Ok(serde_json::from_slice(&triggers)?),
+        None => Ok(vec![]),
+    }
+}
+
+pub(crate) fn save_triggers(store: &dyn EventsStore, triggers: &[PendingTrigger]) -> Result<()> {
+    store.put(TRIGGERS_KEY, serde_json::to_vec(triggers)?)
+}
@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use plum_api_client::HeadChange;
+
+use crate::error::*;
+
+/// Encoding of a head change trace file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One JSON array of head changes per line.
+    Json,
+    /// A sequence of CBOR arrays of head changes.
+    Cbor,
+}
+
+impl TraceFormat {
+    /// Guess the format from the file extension, `.cbor` or JSON lines otherwise.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("cbor") => TraceFormat::Cbor,
+            _ => TraceFormat::Json,
+        }
+    }
+}
+
+/// Write every head change batch handled by `Events` into a trace, which
+/// could be replayed offline with `replay`.
+pub struct HeadChangeRecorder<W: Write = Box<dyn Write>> {
+    writer: W,
+    format: TraceFormat,
+}
+
+impl HeadChangeRecorder {
+    /// Create (or truncate) the trace file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path)?;
+        Ok(HeadChangeRecorder::new(
+            Box::new(BufWriter::new(file)),
+            TraceFormat::from_path(path),
+        ))
+    }
+}
+
+impl<W: Write> HeadChangeRecorder<W> {
+    pub fn new(writer: W, format: TraceFormat) -> Self {
+        HeadChangeRecorder { writer, format }
+    }
+
+    pub fn record(&mut self, changes: &[HeadChange]) -> Result<()> {
+        match self.format {
+            TraceFormat::Json => {
+                serde_json::to_writer(&mut self.writer, changes)?;
+                self.writer.write_all(b"\n")?;
+            }
+            TraceFormat::Cbor => serde_cbor::to_writer(&mut self.writer, changes)?,
+        }
+        // a trace is most useful when the miner crashed, do not lose the tail
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Read all head change batches of a trace.
+pub fn read_trace<R: Read>(reader: R, format: TraceFormat) -> Result<Vec<Vec<HeadChange>>> {
+    match format {
+        TraceFormat::Json => {
+            let mut batches = vec![];
+            for line in BufReader::new(reader).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                batches.push(serde_json::from_str(&line)?);
+            }
+            Ok(batches)
+        }
+        TraceFormat::Cbor => {
+            let batches = serde_cbor::Deserializer::from_reader(reader)
+                .into_iter::<Vec<HeadChange>>()
+                .collect::<std::result::Result<_, _>>()?;
+            Ok(batches)
+        }
+    }
+}
+
+/// Read the trace file at `path`, the format is guessed from the extension.
+pub fn read_trace_file<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<HeadChange>>> {
+    let path = path.as_ref();
+    read_trace(
+        BufReader::new(File::open(path)?),
+        TraceFormat::from_path(path),
+    )
+}
@@ -0,0 +1,41 @@
+use std::any::Any;
+
+use plum_tipset::Tipset;
+
+use crate::error::*;
+
+/// Called when a state change found by the matcher reached the confidence,
+/// with the previous tipset, the tipset the change happened in and the
+/// change. When the trigger timed out it's called with `None` previous
+/// tipset and change. Return `true` to keep watching the state.
+pub type StateChangeHandler<T> =
+    Box<dyn Fn(Option<&Tipset>, &Tipset, Option<&T>, u64) -> Result<bool>>;
+/// Compare the (actor) state after the previous tipset and after the new
+/// one, return the change this trigger is interested in, if any.
+/// The state is usually loaded with `StateApi` using the tipset keys.
+pub type StateMatchFunc<T> = Box<dyn Fn(&Tipset, &Tipset) -> Result<Option<T>>>;
+
+/// `StateChangeHandler` with the change type erased, so that triggers with
+/// different change types could live in one queue.
+pub(crate) type StateHandler =
+    Box<dyn Fn(Option<&Tipset>, &Tipset, Option<&dyn Any>, u64) -> Result<bool>>;
+/// `StateMatchFunc` with the change type erased.
+pub(crate) type StateMatch = Box<dyn Fn(&Tipset, &Tipset) -> Result<Option<Box<dyn Any>>>>;
+
+pub(crate) fn erase_handler<T: 'static>(hnd: StateChangeHandler<T>) -> StateHandler {
+    Box::new(move |prev, ts, change, h| {
+        let change = change.map(|change| {
+            change
+                .downcast_ref::<T>()
+                .expect("the change is produced by the matcher of the same trigger")
+        });
+        hnd(prev, ts, change, h)
+    })
+}
+
+pub(crate) fn erase_matcher<T: 'static>(matcher: StateMatchFunc<T>) -> StateMatch {
+    Box::new(move |prev, ts| {
+        let change = matcher(prev, ts)?;
+        Ok(change.map(|change| Box::new(change) as Box<dyn Any>))
+    })
+}
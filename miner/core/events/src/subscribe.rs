@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use futures::channel::mpsc;
+use plum_tipset::Tipset;
+
+use crate::error::*;
+use crate::tscache::TipSetCache;
+
+/// Item of the stream returned by `Events::subscribe`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TipsetEvent {
+    /// The tipset got `confidence` epochs on top of it.
+    Apply(Tipset),
+    /// A tipset sent in `Apply` before was reverted.
+    Revert(Tipset),
+}
+
+struct Subscriber {
+    confidence: u64,
+    sender: mpsc::UnboundedSender<TipsetEvent>,
+    // tipsets sent in `Apply` which could still be reverted
+    applied: BTreeMap<u64, Tipset>,
+}
+
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    subscribers: Vec<Subscriber>,
+}
+
+impl Subscribers {
+    pub fn add(&mut self, confidence: u64) -> mpsc::UnboundedReceiver<TipsetEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.push(Subscriber {
+            confidence,
+            sender,
+            applied: Default::default(),
+        });
+        receiver
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    pub fn revert(&mut self, ts: &Tipset) {
+        for sub in self.subscribers.iter_mut() {
+            if let Some(applied) = sub.applied.remove(&ts.height()) {
+                let _ = sub.sender.unbounded_send(TipsetEvent::Revert(applied));
+            }
+        }
+        self.subscribers.retain(|sub| !sub.sender.is_closed());
+    }
+
+    /// Called for every height up to the new head (including null rounds),
+    /// in ascending order.
+    pub fn apply(&mut self, h: u64, ts_cache: &TipSetCache, gc_confidence: u64) -> Result<()> {
+        for sub in self.subscribers.iter_mut() {
+            if h < sub.confidence {
+                continue;
+            }
+            let target = h - sub.confidence;
+            if sub.applied.contains_key(&target) {
+                continue;
+            }
+            // storage returns the nearest tipset below a null round
+            match ts_cache.get(target)? {
+                Some(ts) if ts.height() == target => {
+                    let _ = sub.sender.unbounded_send(TipsetEvent::Apply(ts.clone()));
+                    sub.applied.insert(target, ts);
+                }
+                _ => {}
+            }
+            // reverts deeper than gc confidence are not supported by the cache
+            if target > gc_confidence {
+                sub.applied = sub.applied.split_off(&(target - gc_confidence));
+            }
+        }
+        self.subscribers.retain(|sub| !sub.sender.is_closed());
+        Ok(())
+    }
+}
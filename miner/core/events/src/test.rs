@@ -0,0 +1,193 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use proptest::prelude::*;
+
+use plum_tipset::{Tipset, TipsetKey};
+
+use crate::error::EventsError;
+use crate::tscache::TipSetCache;
+
+fn chain(heights: &[u64]) -> Vec<Tipset> {
+    let mut chain: Vec<Tipset> = vec![];
+    for (nonce, h) in heights.iter().enumerate() {
+        let ts = mock_chain::new_tipset(chain.last(), *h, nonce as u64);
+        chain.push(ts);
+    }
+    chain
+}
+
+fn cache(cap: usize) -> TipSetCache {
+    TipSetCache::new(
+        cap,
+        Box::new(|h, _| Err(EventsError::Other(format!("no storage (h={})", h).into()))),
+    )
+}
+
+#[test]
+fn tscache_add_and_get() {
+    let chain = chain(&[0, 1, 2, 3]);
+    let mut tsc = cache(10);
+    assert!(tsc.is_empty());
+    for ts in chain.iter() {
+        tsc.add(ts.clone()).unwrap();
+    }
+    assert_eq!(tsc.best(), &chain[3]);
+    for ts in chain.iter() {
+        assert_eq!(tsc.get(ts.height()).unwrap().as_ref(), Some(ts));
+    }
+    assert!(tsc.get(4).is_err());
+    // not higher than best
+    assert!(tsc.add(chain[2].clone()).is_err());
+}
+
+#[test]
+fn tscache_null_rounds() {
+    let chain = chain(&[0, 1, 4]);
+    let mut tsc = cache(10);
+    for ts in chain.iter() {
+        tsc.add(ts.clone()).unwrap();
+    }
+    assert_eq!(tsc.get(2).unwrap(), None);
+    assert_eq!(tsc.get(3).unwrap(), None);
+    assert_eq!(tsc.get_non_null(2).unwrap(), chain[2]);
+    assert_eq!(tsc.get_non_null(1).unwrap(), chain[1]);
+}
+
+#[test]
+fn tscache_revert() {
+    let chain = chain(&[0, 1, 2]);
+    let mut tsc = cache(10);
+    for ts in chain.iter() {
+        tsc.add(ts.clone()).unwrap();
+    }
+    // only the best tipset could be reverted
+    assert!(tsc.revert(&chain[1]).is_err());
+    tsc.revert(&chain[2]).unwrap();
+    assert_eq!(tsc.best(), &chain[1]);
+    assert!(tsc.get(2).is_err());
+
+    assert_eq!(tsc.get_by_key(chain[2].key()), None);
+
+    let fork = mock_chain::new_tipset(Some(&chain[1]), 2, 100);
+    tsc.add(fork.clone()).unwrap();
+    assert_eq!(tsc.get(2).unwrap(), Some(fork.clone()));
+    assert_eq!(tsc.get_by_key(fork.key()), Some(fork));
+}
+
+#[test]
+fn tscache_revert_null_rounds() {
+    let chain = chain(&[0, 1, 4]);
+    let mut tsc = cache(10);
+    for ts in chain.iter() {
+        tsc.add(ts.clone()).unwrap();
+    }
+    tsc.revert(&chain[2]).unwrap();
+    assert_eq!(tsc.best(), &chain[1]);
+    assert!(tsc.get(2).is_err());
+}
+
+#[test]
+fn tscache_wrap_around() {
+    let chain = chain(&(0..10).collect::<Vec<_>>());
+    let mut tsc = cache(3);
+    for ts in chain.iter() {
+        tsc.add(ts.clone()).unwrap();
+    }
+    for ts in chain[7..].iter() {
+        assert_eq!(tsc.get(ts.height()).unwrap().as_ref(), Some(ts));
+        assert_eq!(tsc.get_by_key(ts.key()).as_ref(), Some(ts));
+    }
+    // overwritten
+    assert_eq!(tsc.get_by_key(chain[6].key()), None);
+    assert!(tsc.get(6).is_err());
+
+    // revert until the cache is empty, then start again
+    for ts in chain[7..].iter().rev() {
+        tsc.revert(ts).unwrap();
+    }
+    assert!(tsc.is_empty());
+    tsc.add(chain[9].clone()).unwrap();
+    assert_eq!(tsc.best(), &chain[9]);
+}
+
+#[derive(Clone, Debug)]
+enum Op {
+    Apply { null_rounds: u64 },
+    Revert { depth: usize },
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        2 => (0u64..4).prop_map(|null_rounds| Op::Apply { null_rounds }),
+        1 => (1usize..4).prop_map(|depth| Op::Revert { depth }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn tscache_random_reorgs(cap in 1usize..8, ops in prop::collection::vec(op(), 1..64)) {
+        // the canonical chain, the storage finds tipsets below the cache in it
+        let model = Rc::new(RefCell::new(vec![mock_chain::new_tipset(None, 0, 0)]));
+        let storage_model = model.clone();
+        let mut tsc = TipSetCache::new(
+            cap,
+            Box::new(move |h, _: &TipsetKey| {
+                let model = storage_model.borrow();
+                Ok(model.iter().rev().find(|ts| ts.height() <= h).unwrap().clone())
+            }),
+        );
+        tsc.add(model.borrow()[0].clone()).unwrap();
+
+        let mut reverted = vec![];
+        for (nonce, op) in ops.into_iter().enumerate() {
+            let mut model = model.borrow_mut();
+            match op {
+                Op::Apply { null_rounds } => {
+                    let best = model.last().unwrap();
+                    let height = best.height() + null_rounds + 1;
+                    let ts = mock_chain::new_tipset(Some(best), height, nonce as u64 + 1);
+                    tsc.add(ts.clone()).unwrap();
+                    model.push(ts);
+                }
+                Op::Revert { depth } => {
+                    // only revert within the cache, as `Events` does
+                    if depth >= model.len() || tsc.get_by_key(model[model.len() - 1 - depth].key()).is_none() {
+                        continue;
+                    }
+                    for _ in 0..depth {
+                        let ts = model.pop().unwrap();
+                        tsc.revert(&ts).unwrap();
+                        reverted.push(ts);
+                    }
+                }
+            }
+        }
+
+        let model = model.borrow();
+        let best = model.last().unwrap();
+        prop_assert_eq!(tsc.best(), best);
+        prop_assert!(tsc.get(best.height() + 1).is_err());
+        prop_assert_eq!(tsc.get_by_key(best.key()).as_ref(), Some(best));
+        for h in 0..=best.height() {
+            let expected = model.iter().rev().find(|ts| ts.height() <= h).unwrap();
+            match tsc.get(h).unwrap() {
+                // exact, or the nearest one below from storage
+                Some(ts) => prop_assert_eq!(&ts, expected),
+                // null round in the cache
+                None => prop_assert!(expected.height() < h),
+            }
+            let next = model.iter().find(|ts| ts.height() >= h).unwrap();
+            let non_null = tsc.get_non_null(h).unwrap();
+            prop_assert!(&non_null == next || &non_null == expected);
+        }
+        for ts in model.iter() {
+            if let Some(cached) = tsc.get_by_key(ts.key()) {
+                prop_assert_eq!(&cached, ts);
+            }
+        }
+        for ts in reverted.iter() {
+            prop_assert_eq!(tsc.get_by_key(ts.key()), None);
+        }
+    }
+}
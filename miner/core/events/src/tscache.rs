@@ -1,5 +1,6 @@
-use std::collections::BTreeMap;
+use std::collections::HashMap;
 
+use cid::Cid;
 use plum_tipset::{Tipset, TipsetKey};
 
 use crate::error::{EventsError, Result};
@@ -7,54 +8,72 @@ use crate::error::{EventsError, Result};
 use crate::TARGET;
 use log::warn;
 
+/// Get the tipset at the height (or the nearest one below it for null
+/// rounds) on the chain of the key, used for heights not in the cache.
+pub type StorageFunc = Box<dyn Fn(u64, &TipsetKey) -> Result<Tipset>>;
+
+/// Fixed-capacity ring buffer of the latest `capacity` heights, null rounds
+/// take a slot too. The slot of a height is found from its distance to the
+/// best height, so lookups by height or key are O(1).
 pub struct TipSetCache {
-    cache: BTreeMap<usize, Tipset>,
+    // `None` for null rounds
+    slots: Vec<Option<Tipset>>,
+    // slot of the best tipset
     start: usize,
     len: usize,
-    capacity: usize,
-    storage: Box<dyn Fn(u64, &TipsetKey) -> Result<Tipset>>,
+    best_height: u64,
+    // key of every tipset in the cache -> its height
+    heights: HashMap<Vec<Cid>, u64>,
+    storage: StorageFunc,
 }
 
 impl TipSetCache {
-    pub fn new(cap: usize, storage: Box<dyn Fn(u64, &TipsetKey) -> Result<Tipset>>) -> Self {
+    pub fn new(cap: usize, storage: StorageFunc) -> Self {
+        assert!(cap > 0, "capacity of tipset cache must not be 0");
         TipSetCache {
-            cache: BTreeMap::new(),
+            slots: vec![None; cap],
             start: 0,
             len: 0,
-            capacity: cap,
+            best_height: 0,
+            heights: HashMap::with_capacity(cap),
             storage,
         }
     }
 
     pub fn add(&mut self, tipset: Tipset) -> Result<()> {
-        let mut next_height = tipset.height();
         if self.len > 0 {
-            let best = self.best();
-            let best_height = best.height();
-            if best_height >= next_height {
-                return Err(EventsError::HigherThenBest(best_height + 1, next_height));
+            if self.best_height >= tipset.height() {
+                return Err(EventsError::HigherThenBest(
+                    self.best_height + 1,
+                    tipset.height(),
+                ));
             }
-            next_height = best_height + 1;
-        }
-        // full null block
-        while next_height != tipset.height() {
-            self.start = normal_modulo(self.start + 1, self.capacity);
-            self.cache.remove(&self.start);
-            if self.len < self.capacity {
-                self.len += 1;
+            // null rounds, older ones would be overwritten anyway
+            let nulls = tipset.height() - self.best_height - 1;
+            for _ in 0..std::cmp::min(nulls, self.capacity() as u64) {
+                self.push(None);
             }
-            next_height += 1;
         }
+        self.best_height = tipset.height();
+        self.push(Some(tipset));
+        Ok(())
+    }
 
-        self.start = normal_modulo(self.start + 1, self.capacity);
-        self.cache.insert(self.start, tipset);
-        if self.len < self.capacity {
+    fn push(&mut self, slot: Option<Tipset>) {
+        self.start = (self.start + 1) % self.capacity();
+        let old = std::mem::replace(&mut self.slots[self.start], slot);
+        if let Some(old) = old {
+            self.heights.remove(old.cids());
+        }
+        if let Some(ts) = &self.slots[self.start] {
+            self.heights.insert(ts.cids().to_vec(), ts.height());
+        }
+        if self.len < self.capacity() {
             self.len += 1;
         }
-
-        Ok(())
     }
 
+    /// Revert the best tipset, and the null rounds before it.
     pub fn revert(&mut self, tipset: &Tipset) -> Result<()> {
         if self.len == 0 {
             return Ok(());
@@ -65,12 +84,29 @@ impl TipSetCache {
                 tipset.clone(),
             ));
         }
-        self.cache.remove(&self.start);
-        self.start = normal_modulo(self.start - 1, self.capacity);
-        self.len -= 1;
+        self.pop();
+        while self.len > 0 && self.slots[self.start].is_none() {
+            self.pop();
+        }
         Ok(())
     }
 
+    fn pop(&mut self) {
+        if let Some(old) = self.slots[self.start].take() {
+            self.heights.remove(old.cids());
+        }
+        self.start = (self.start + self.capacity() - 1) % self.capacity();
+        self.len -= 1;
+        self.best_height = self.best_height.saturating_sub(1);
+    }
+
+    fn slot(&self, height: u64) -> &Option<Tipset> {
+        let distance = (self.best_height - height) as usize;
+        &self.slots[(self.start + self.capacity() - distance) % self.capacity()]
+    }
+
+    /// Get the tipset at `height`, `None` if it's a null round. Heights below
+    /// the cache are requested from storage.
     pub fn get(&self, height: u64) -> Result<Option<Tipset>> {
         if self.len == 0 {
             warn!(
@@ -79,60 +115,66 @@ impl TipSetCache {
             );
             return (self.storage)(height, &TipsetKey::default()).map(Some);
         }
-        let best_height = self.best().height();
-        if height > best_height {
-            return Err(EventsError::NotInCache(best_height, height));
+        if height > self.best_height {
+            return Err(EventsError::NotInCache(self.best_height, height));
         }
-        let get_tail = || {
-            for i in 1..(self.len + 1) {
-                if let Some(t) = self
-                    .cache
-                    .get(&normal_modulo(self.start - self.len + i, self.capacity))
-                {
-                    return Some(t);
-                }
-            }
-            None
-        };
-        let tail = get_tail().ok_or(EventsError::GetTailFailed)?;
-        if height < tail.height() {
+        let tail_height = self.best_height + 1 - self.len as u64;
+        if height < tail_height {
+            let tail = (tail_height..=self.best_height)
+                .find_map(|h| self.slot(h).as_ref())
+                .ok_or(EventsError::GetTailFailed)?;
             warn!(target: TARGET, "tipSetCache.get: requested tipset not in cache, requesting from storage (h={}; tail={})", height, tail.height());
             return (self.storage)(height, tail.key()).map(Some);
         }
-        let tipset = self
-            .cache
-            .get(&normal_modulo(
-                self.start - (best_height - height) as usize,
-                self.capacity,
-            ))
-            .map(Clone::clone);
-        Ok(tipset)
+        Ok(self.slot(height).clone())
     }
 
+    /// Get the tipset at `height`, or the first one after it if it's a null round.
     pub fn get_non_null(&self, height: u64) -> Result<Tipset> {
         let mut height = height;
-        let mut final_check = 0;
         loop {
-            if final_check > self.capacity {
-                return Err(EventsError::OverflowCacheCapacity(
-                    final_check,
-                    self.capacity,
-                ));
-            }
-            let tipset = self.get(height)?;
-            if let Some(t) = tipset {
-                return Ok(t);
+            if let Some(ts) = self.get(height)? {
+                return Ok(ts);
             }
+            // the best tipset is never null, so this stops there
             height += 1;
-            final_check += 1;
         }
     }
 
-    pub fn best(&self) -> &Tipset {
-        self.cache.get(&self.start).expect("best must exist")
+    /// Get the tipset with `key` if it's in the cache.
+    pub fn get_by_key(&self, key: &TipsetKey) -> Option<Tipset> {
+        let height = *self.heights.get(key.cids())?;
+        self.slot(height).clone()
     }
-}
 
-fn normal_modulo(n: usize, m: usize) -> usize {
-    ((n % m) + m) % m
+    /// All tipsets in the cache, from the best one downwards.
+    pub fn tipsets(&self) -> Vec<Tipset> {
+        if self.len == 0 {
+            return vec![];
+        }
+        let tail_height = self.best_height + 1 - self.len as u64;
+        (tail_height..=self.best_height)
+            .rev()
+            .filter_map(|h| self.slot(h).clone())
+            .collect()
+    }
+
+    /// Drop all tipsets.
+    pub fn clear(&mut self) {
+        while self.len > 0 {
+            self.pop();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn best(&self) -> &Tipset {
+        self.slots[self.start].as_ref().expect("best must exist")
+    }
 }
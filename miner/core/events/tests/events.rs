@@ -0,0 +1,775 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use futures::future::{self, FutureExt};
+use futures::stream::StreamExt;
+
+use async_tools::task_manager::ServiceTaskExecutor;
+use events::error::EventsError;
+use events::{Events, HeadChangeRecorder, TipsetEvent, TraceFormat};
+use mock_chain::MockChain;
+use plum_message::UnsignedMessage;
+use plum_tipset::Tipset;
+
+fn executor() -> ServiceTaskExecutor {
+    Arc::new(|fut| {
+        async_std::task::spawn(fut);
+    })
+}
+
+/// Events fed with the genesis of a new mock chain.
+fn setup() -> (Arc<MockChain>, Arc<RwLock<Events>>) {
+    let chain = Arc::new(MockChain::new());
+    let (events, _listen) = Events::new(chain.clone(), executor());
+    events
+        .write()
+        .unwrap()
+        .head_change(vec![], vec![chain.head()])
+        .unwrap();
+    (chain, events)
+}
+
+fn apply(events: &Arc<RwLock<Events>>, tipsets: Vec<Tipset>) {
+    events
+        .write()
+        .unwrap()
+        .head_change(vec![], tipsets)
+        .unwrap();
+}
+
+type Calls = Rc<RefCell<Vec<u64>>>;
+
+/// Register a height trigger recording the heights of applied and reverted tipsets.
+fn chain_at(events: &Arc<RwLock<Events>>, confidence: u64, h: u64) -> (u64, Calls, Calls) {
+    let applied = Calls::default();
+    let reverted = Calls::default();
+    let (a, r) = (applied.clone(), reverted.clone());
+    let id = events
+        .write()
+        .unwrap()
+        .chain_at(
+            Box::new(move |ts, _| {
+                a.borrow_mut().push(ts.height());
+                Ok(())
+            }),
+            Box::new(move |ts| {
+                r.borrow_mut().push(ts.height());
+                Ok(())
+            }),
+            confidence,
+            h,
+        )
+        .unwrap();
+    (id, applied, reverted)
+}
+
+#[test]
+fn chain_at_waits_for_confidence() {
+    let (chain, events) = setup();
+    let (_, applied, _) = chain_at(&events, 2, 3);
+
+    apply(&events, chain.append_n(4));
+    assert!(applied.borrow().is_empty());
+    apply(&events, vec![chain.append()]);
+    assert_eq!(*applied.borrow(), vec![3]);
+
+    // called only once
+    apply(&events, chain.append_n(3));
+    assert_eq!(*applied.borrow(), vec![3]);
+
+    // registered after the confidence is reached, called immediately
+    let (_, applied, _) = chain_at(&events, 2, 4);
+    assert_eq!(*applied.borrow(), vec![4]);
+}
+
+#[test]
+fn chain_at_reorg() {
+    let (chain, events) = setup();
+    let (_, applied, reverted) = chain_at(&events, 1, 3);
+    apply(&events, chain.append_n(5));
+    assert_eq!(*applied.borrow(), vec![3]);
+
+    let (reverts, applies) = chain.reorg(3, 4);
+    events
+        .write()
+        .unwrap()
+        .head_change(reverts, applies)
+        .unwrap();
+    assert_eq!(*reverted.borrow(), vec![3]);
+    assert_eq!(*applied.borrow(), vec![3, 3]);
+    assert_ne!(chain.tipset_at(3), None);
+}
+
+#[test]
+fn chain_at_null_rounds() {
+    let (chain, events) = setup();
+    let (_, applied, _) = chain_at(&events, 0, 3);
+    apply(&events, chain.append_n(2));
+    // 3 and 4 are null rounds
+    let ts = chain.append_after_null_rounds(2);
+    apply(&events, vec![ts]);
+    assert_eq!(*applied.borrow(), vec![5]);
+    assert_eq!(chain.tipset_at(3), None);
+}
+
+#[test]
+fn cancel_and_multiple_triggers() {
+    let (chain, events) = setup();
+    let (id, cancelled, _) = chain_at(&events, 0, 2);
+    let (_, first, _) = chain_at(&events, 0, 2);
+    let (_, second, _) = chain_at(&events, 0, 2);
+    assert!(events.write().unwrap().cancel(id));
+    assert!(!events.write().unwrap().cancel(id));
+
+    apply(&events, chain.append_n(2));
+    assert!(cancelled.borrow().is_empty());
+    assert_eq!(*first.borrow(), vec![2]);
+    assert_eq!(*second.borrow(), vec![2]);
+}
+
+#[test]
+fn chain_at_before_first_head_change() {
+    let chain = Arc::new(MockChain::new());
+    let (events, _listen) = Events::new(chain.clone(), executor());
+    let r = events
+        .write()
+        .unwrap()
+        .chain_at(Box::new(|_, _| Ok(())), Box::new(|_| Ok(())), 0, 1);
+    assert!(matches!(r, Err(EventsError::EmptyCache)));
+
+    // the failed registration took no id
+    apply(&events, vec![chain.head()]);
+    let (id, _, _) = chain_at(&events, 0, 1);
+    assert_eq!(id, 0);
+}
+
+type HeightPairs = Rc<RefCell<Vec<(u64, u64)>>>;
+
+/// Register a message trigger for `msg`, recording `(applied height, height
+/// called at)` of matched messages and `(timeout height, height called at)`
+/// of timeouts, and the heights of reverted tipsets.
+fn called(
+    events: &Arc<RwLock<Events>>,
+    msg: &UnsignedMessage,
+    confidence: u64,
+    timeout: u64,
+) -> (HeightPairs, HeightPairs, Calls) {
+    let matched = HeightPairs::default();
+    let timeouts = HeightPairs::default();
+    let reverted = Calls::default();
+    let (m, t, r) = (matched.clone(), timeouts.clone(), reverted.clone());
+    let cid = msg.cid();
+    events
+        .write()
+        .unwrap()
+        .called(
+            Box::new(|_| Ok((false, true))),
+            Box::new(move |msg, receipt, ts, h| {
+                match (msg, receipt) {
+                    (Some(_), Some(_)) => m.borrow_mut().push((ts.height(), h)),
+                    _ => t.borrow_mut().push((ts.height(), h)),
+                }
+                Ok(true)
+            }),
+            Box::new(move |ts| {
+                r.borrow_mut().push(ts.height());
+                Ok(())
+            }),
+            confidence,
+            timeout,
+            Box::new(move |msg| Ok(msg.cid() == cid)),
+        )
+        .unwrap();
+    (matched, timeouts, reverted)
+}
+
+#[test]
+fn called_needs_current_head() {
+    let (events, _listen) = Events::new(Arc::new(MockChain::new()), executor());
+    let msg = mock_chain::new_message(0);
+    let res = events.write().unwrap().called(
+        Box::new(|_| Ok((false, true))),
+        Box::new(|_, _, _, _| Ok(true)),
+        Box::new(|_| Ok(())),
+        0,
+        events::NO_TIMEOUT,
+        Box::new(move |m| Ok(m.cid() == msg.cid())),
+    );
+    assert!(res.is_err());
+}
+
+#[test]
+fn called_matches_every_trigger() {
+    let (chain, events) = setup();
+    let msg = mock_chain::new_message(1);
+    let (first, _, _) = called(&events, &msg, 0, events::NO_TIMEOUT);
+    let (second, _, _) = called(&events, &msg, 0, events::NO_TIMEOUT);
+    let (other, _, _) = called(&events, &mock_chain::new_message(2), 0, events::NO_TIMEOUT);
+
+    // included at height 1, applied at height 2
+    chain.push_message(msg, mock_chain::new_receipt(0));
+    apply(&events, chain.append_n(2));
+    assert_eq!(*first.borrow(), vec![(2, 2)]);
+    assert_eq!(*second.borrow(), vec![(2, 2)]);
+    assert!(other.borrow().is_empty());
+}
+
+#[test]
+fn called_waits_for_confidence() {
+    let (chain, events) = setup();
+    let msg = mock_chain::new_message(1);
+    let (matched, timeouts, _) = called(&events, &msg, 2, events::NO_TIMEOUT);
+
+    chain.push_message(msg, mock_chain::new_receipt(0));
+    apply(&events, chain.append_n(3));
+    assert!(matched.borrow().is_empty());
+    apply(&events, vec![chain.append()]);
+    assert_eq!(*matched.borrow(), vec![(2, 4)]);
+
+    apply(&events, chain.append_n(3));
+    assert_eq!(*matched.borrow(), vec![(2, 4)]);
+    assert!(timeouts.borrow().is_empty());
+}
+
+#[test]
+fn called_timeout() {
+    let (chain, events) = setup();
+    let msg = mock_chain::new_message(1);
+    let (matched, timeouts, _) = called(&events, &msg, 1, 3);
+
+    apply(&events, chain.append_n(3));
+    assert!(timeouts.borrow().is_empty());
+    apply(&events, vec![chain.append()]);
+    assert_eq!(*timeouts.borrow(), vec![(3, 4)]);
+
+    // the message landing after the timeout is still delivered, as the
+    // handler asked for more
+    chain.push_message(msg, mock_chain::new_receipt(0));
+    apply(&events, chain.append_n(3));
+    assert_eq!(*matched.borrow(), vec![(6, 7)]);
+    assert_eq!(*timeouts.borrow(), vec![(3, 4)]);
+}
+
+#[test]
+fn called_timeout_not_fired_after_match() {
+    let (chain, events) = setup();
+    let msg = mock_chain::new_message(1);
+    let (matched, timeouts, _) = called(&events, &msg, 1, 3);
+
+    chain.push_message(msg, mock_chain::new_receipt(0));
+    apply(&events, chain.append_n(6));
+    assert_eq!(*matched.borrow(), vec![(2, 3)]);
+    assert!(timeouts.borrow().is_empty());
+}
+
+#[test]
+fn called_reorg() {
+    let (chain, events) = setup();
+    let msg = mock_chain::new_message(1);
+    let (matched, _, reverted) = called(&events, &msg, 1, events::NO_TIMEOUT);
+
+    chain.push_message(msg, mock_chain::new_receipt(0));
+    apply(&events, chain.append_n(3));
+    assert_eq!(*matched.borrow(), vec![(2, 3)]);
+
+    // the message is still included at height 1, but executed again on
+    // the fork
+    let (reverts, applies) = chain.reorg(2, 3);
+    events
+        .write()
+        .unwrap()
+        .head_change(reverts, applies)
+        .unwrap();
+    assert_eq!(*reverted.borrow(), vec![2]);
+    assert_eq!(*matched.borrow(), vec![(2, 3), (2, 3)]);
+}
+
+#[test]
+fn called_timeout_after_revert() {
+    let (chain, events) = setup();
+    let msg = mock_chain::new_message(1);
+    let (matched, timeouts, reverted) = called(&events, &msg, 1, 5);
+
+    chain.push_message(msg, mock_chain::new_receipt(0));
+    apply(&events, chain.append_n(3));
+    assert_eq!(*matched.borrow(), vec![(2, 3)]);
+
+    // the fork drops the message, so the trigger times out
+    let (reverts, applies) = chain.reorg(3, 3);
+    events
+        .write()
+        .unwrap()
+        .head_change(reverts, applies)
+        .unwrap();
+    assert_eq!(*reverted.borrow(), vec![2]);
+    apply(&events, chain.append_n(2));
+    assert!(timeouts.borrow().is_empty());
+    apply(&events, vec![chain.append()]);
+    assert_eq!(*timeouts.borrow(), vec![(5, 6)]);
+    assert_eq!(*matched.borrow(), vec![(2, 3)]);
+}
+
+type Journal = Arc<Mutex<Vec<String>>>;
+
+/// Register an async height trigger journaling its calls, the apply future
+/// takes `apply_delay` to finish.
+fn chain_at_async(
+    events: &Arc<RwLock<Events>>,
+    h: u64,
+    apply_delay: Duration,
+    timeout: Duration,
+) -> (u64, Journal) {
+    let journal = Journal::default();
+    let (a, r) = (journal.clone(), journal.clone());
+    let id = events
+        .write()
+        .unwrap()
+        .chain_at_async(
+            Box::new(move |ts, _| {
+                let a = a.clone();
+                async move {
+                    async_std::task::sleep(apply_delay).await;
+                    a.lock().unwrap().push(format!("apply {}", ts.height()));
+                    Ok(())
+                }
+                .boxed()
+            }),
+            Box::new(move |ts| {
+                let r = r.clone();
+                async move {
+                    r.lock().unwrap().push(format!("revert {}", ts.height()));
+                    Ok(())
+                }
+                .boxed()
+            }),
+            0,
+            h,
+            timeout,
+        )
+        .unwrap();
+    (id, journal)
+}
+
+#[test]
+fn chain_at_async_in_order() {
+    let (chain, events) = setup();
+    let (_, journal) = chain_at_async(
+        &events,
+        2,
+        Duration::from_millis(200),
+        Duration::from_secs(5),
+    );
+
+    apply(&events, chain.append_n(2));
+    // the revert is queued after the slow apply
+    let (reverts, applies) = chain.reorg(1, 1);
+    events
+        .write()
+        .unwrap()
+        .head_change(reverts, applies)
+        .unwrap();
+    assert!(journal.lock().unwrap().is_empty());
+
+    let done = async {
+        for _ in 0..50 {
+            if journal.lock().unwrap().len() == 3 {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    async_std::task::block_on(done);
+    assert_eq!(
+        *journal.lock().unwrap(),
+        vec!["apply 2", "revert 2", "apply 2"]
+    );
+}
+
+#[test]
+fn chain_at_async_handler_errors() {
+    let (chain, events) = setup();
+    let mut errors = events.write().unwrap().handler_errors();
+    let failed = events
+        .write()
+        .unwrap()
+        .chain_at_async(
+            Box::new(|_, _| future::ready(Err(EventsError::Other("failed".into()))).boxed()),
+            Box::new(|_| future::ready(Ok(())).boxed()),
+            0,
+            1,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+    let (slow, _) = chain_at_async(
+        &events,
+        2,
+        Duration::from_secs(5),
+        Duration::from_millis(100),
+    );
+
+    apply(&events, chain.append_n(2));
+    let mut next = || {
+        async_std::task::block_on(async_std::future::timeout(
+            Duration::from_secs(5),
+            errors.next(),
+        ))
+        .unwrap()
+        .unwrap()
+    };
+    let err = next();
+    assert_eq!((err.trigger, err.height, err.revert), (failed, 1, false));
+    let err = next();
+    assert_eq!((err.trigger, err.height, err.revert), (slow, 2, false));
+    match err.error {
+        EventsError::HandlerTimeout(_) => {}
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn subscribe_with_confidence() {
+    let (chain, events) = setup();
+    let mut sub = events.write().unwrap().subscribe(1);
+    // the head applied before subscribing gets the confidence after it
+    let genesis = chain.head();
+    let tipsets = chain.append_n(3);
+    apply(&events, tipsets.clone());
+    assert_eq!(sub.try_next().unwrap(), Some(TipsetEvent::Apply(genesis)));
+    assert_eq!(
+        sub.try_next().unwrap(),
+        Some(TipsetEvent::Apply(tipsets[0].clone()))
+    );
+    assert_eq!(
+        sub.try_next().unwrap(),
+        Some(TipsetEvent::Apply(tipsets[1].clone()))
+    );
+    assert!(sub.try_next().is_err());
+
+    let (reverts, applies) = chain.reorg(2, 2);
+    events
+        .write()
+        .unwrap()
+        .head_change(reverts, applies)
+        .unwrap();
+    assert_eq!(
+        sub.try_next().unwrap(),
+        Some(TipsetEvent::Revert(tipsets[1].clone()))
+    );
+    assert_eq!(
+        sub.try_next().unwrap(),
+        Some(TipsetEvent::Apply(chain.tipset_at(2).unwrap()))
+    );
+}
+
+#[test]
+fn subscribe_skips_confident_tipsets() {
+    let (chain, events) = setup();
+    apply(&events, chain.append_n(3));
+    let mut sub = events.write().unwrap().subscribe(1);
+    let ts = chain.append();
+    apply(&events, vec![ts]);
+    // only the tipset getting the confidence now, not the ones before
+    assert_eq!(
+        sub.try_next().unwrap(),
+        Some(TipsetEvent::Apply(chain.tipset_at(3).unwrap()))
+    );
+    assert!(sub.try_next().is_err());
+}
+
+#[test]
+fn resync_after_reconnect() {
+    let chain = Arc::new(MockChain::new());
+    let (events, listen) = Events::new(chain.clone(), executor());
+    let listen = Box::into_pin(listen);
+
+    let test = async {
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        let (_, applied, reverted) = chain_at(&events, 0, 2);
+        chain.append_n(3);
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*applied.borrow(), vec![2]);
+
+        // head changes while disconnected are synthesized after reconnecting
+        chain.disconnect();
+        chain.reorg(2, 3);
+        for _ in 0..50 {
+            if events.read().unwrap().reconnects() > 0 && applied.borrow().len() == 2 {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*reverted.borrow(), vec![2]);
+        assert_eq!(*applied.borrow(), vec![2, 2]);
+    };
+    async_std::task::block_on(future::select(listen, test.boxed_local()));
+}
+
+#[test]
+fn reset_after_too_deep_resync() {
+    let chain = Arc::new(MockChain::new());
+    let (events, listen) = Events::new(chain.clone(), executor());
+    let listen = Box::into_pin(listen);
+    // the resync depth limit, which is the cache capacity too
+    let depth = 2 * plum_params::params().fork_length_threshold as usize;
+
+    let test = async {
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        let h = depth as u64;
+        let (_, applied, reverted) = chain_at(&events, 0, h);
+        chain.append_n(depth + 1);
+        for _ in 0..50 {
+            if !applied.borrow().is_empty() {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*applied.borrow(), vec![h]);
+
+        // the fork is deeper than the cache, the cached chain is dropped
+        chain.disconnect();
+        chain.reorg(depth + 1, depth + 2);
+        for _ in 0..50 {
+            if events.read().unwrap().reconnects() > 0 && !reverted.borrow().is_empty() {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*reverted.borrow(), vec![h]);
+
+        // and head changes on top of the new head work again
+        let next = chain.head().height() + 1;
+        let (_, applied, _) = chain_at(&events, 0, next);
+        chain.append();
+        for _ in 0..50 {
+            if !applied.borrow().is_empty() {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*applied.borrow(), vec![next]);
+    };
+    async_std::task::block_on(future::select(listen, test.boxed_local()));
+}
+
+#[test]
+fn reset_after_resync_across_null_rounds() {
+    let chain = Arc::new(MockChain::new());
+    let (events, listen) = Events::new(chain.clone(), executor());
+    let listen = Box::into_pin(listen);
+    let depth = 2 * plum_params::params().fork_length_threshold;
+
+    let test = async {
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        let h = depth + 2;
+        let (_, applied, reverted) = chain_at(&events, 0, h);
+        chain.append();
+        // the null rounds are wider than the cache
+        chain.append_after_null_rounds(depth);
+        for _ in 0..50 {
+            if !applied.borrow().is_empty() {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*applied.borrow(), vec![h]);
+
+        // only two tipsets are reverted, but they span more heights than
+        // the cache, so the cached chain is dropped
+        chain.disconnect();
+        chain.reorg(2, 3);
+        for _ in 0..50 {
+            if events.read().unwrap().reconnects() > 0 && !reverted.borrow().is_empty() {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*reverted.borrow(), vec![h]);
+
+        let next = chain.head().height() + 1;
+        let (_, applied, _) = chain_at(&events, 0, next);
+        chain.append();
+        for _ in 0..50 {
+            if !applied.borrow().is_empty() {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*applied.borrow(), vec![next]);
+    };
+    async_std::task::block_on(future::select(listen, test.boxed_local()));
+}
+
+#[test]
+fn state_changed_with_confidence() {
+    let (chain, events) = setup();
+    let applied = Rc::new(RefCell::new(vec![]));
+    let reverted = Calls::default();
+    let (a, r) = (applied.clone(), reverted.clone());
+    events
+        .write()
+        .unwrap()
+        .state_changed(
+            Box::new(|_| Ok((false, true))),
+            Box::new(move |prev, ts, change: Option<&(u64, u64)>, _| {
+                a.borrow_mut()
+                    .push((prev.map(|p| p.height()), ts.height(), change.cloned()));
+                Ok(true)
+            }),
+            Box::new(move |ts| {
+                r.borrow_mut().push(ts.height());
+                Ok(())
+            }),
+            1,
+            events::NO_TIMEOUT,
+            // pretend the watched state changes at height 3
+            Box::new(|prev, ts| {
+                Ok(if ts.height() == 3 {
+                    Some((prev.height(), ts.height()))
+                } else {
+                    None
+                })
+            }),
+        )
+        .unwrap();
+
+    apply(&events, chain.append_n(3));
+    assert!(applied.borrow().is_empty());
+    apply(&events, vec![chain.append()]);
+    assert_eq!(*applied.borrow(), vec![(Some(2), 3, Some((2, 3)))]);
+
+    let (reverts, applies) = chain.reorg(2, 2);
+    events
+        .write()
+        .unwrap()
+        .head_change(reverts, applies)
+        .unwrap();
+    assert_eq!(*reverted.borrow(), vec![3]);
+    assert_eq!(applied.borrow().len(), 2);
+}
+
+#[test]
+fn record_and_replay() {
+    use plum_api_client::{HeadChange, HeadChangeType};
+
+    let chain = MockChain::new();
+    let change = |r#type, val| HeadChange { r#type, val };
+    let mut batches = vec![vec![change(HeadChangeType::Current, chain.head())]];
+    for ts in chain.append_n(5) {
+        batches.push(vec![change(HeadChangeType::Apply, ts)]);
+    }
+    let (reverts, applies) = chain.reorg(3, 4);
+    batches.push(
+        reverts
+            .into_iter()
+            .map(|ts| change(HeadChangeType::Revert, ts))
+            .chain(
+                applies
+                    .into_iter()
+                    .map(|ts| change(HeadChangeType::Apply, ts)),
+            )
+            .collect(),
+    );
+
+    for format in vec![TraceFormat::Json, TraceFormat::Cbor] {
+        let mut recorder = HeadChangeRecorder::new(vec![], format);
+        for batch in batches.iter() {
+            recorder.record(batch).unwrap();
+        }
+        let trace = events::read_trace(&recorder.into_inner()[..], format).unwrap();
+        assert_eq!(trace, batches);
+
+        // replay offline, the api is only used on cache misses
+        let (events, _listen) = Events::new(Arc::new(MockChain::new()), executor());
+        let mut trace = trace.into_iter();
+        let current = trace.next().into_iter().collect();
+        events.write().unwrap().replay(current).unwrap();
+        let (_, applied, reverted) = chain_at(&events, 1, 3);
+        events.write().unwrap().replay(trace.collect()).unwrap();
+        assert_eq!(*applied.borrow(), vec![3, 3]);
+        assert_eq!(*reverted.borrow(), vec![3]);
+    }
+}
+
+#[test]
+fn persist_and_restore() {
+    use datastore::basic_ds::new_map_datastore;
+    use events::{PendingTrigger, TriggerInfo};
+
+    let (chain, events) = setup();
+    let pending = events
+        .write()
+        .unwrap()
+        .restore(Box::new(new_map_datastore()))
+        .unwrap();
+    assert!(pending.is_empty());
+
+    let info = |purpose: &str| TriggerInfo {
+        sector_id: Some(1),
+        purpose: purpose.to_string(),
+    };
+    let (fired, _, _) = chain_at(&events, 1, 2);
+    let (waiting, _, _) = chain_at(&events, 1, 10);
+    events
+        .write()
+        .unwrap()
+        .describe_trigger(fired, info("fired"));
+    events
+        .write()
+        .unwrap()
+        .describe_trigger(waiting, info("waiting"));
+    apply(&events, chain.append_n(5));
+    let store = events.write().unwrap().take_store().unwrap();
+
+    let (restored, _listen) = Events::new(chain.clone(), executor());
+    let pending = restored.write().unwrap().restore(store).unwrap();
+    assert_eq!(
+        pending,
+        vec![
+            PendingTrigger {
+                info: info("fired"),
+                height: Some(2),
+                confidence: 1,
+                called: true,
+            },
+            PendingTrigger {
+                info: info("waiting"),
+                height: Some(10),
+                confidence: 1,
+                called: false,
+            },
+        ]
+    );
+    // the cache window is restored, no need to request the chain
+    let (_, applied, _) = chain_at(&restored, 1, 3);
+    assert_eq!(*applied.borrow(), vec![3]);
+    apply(&restored, vec![chain.append()]);
+}
+
+#[test]
+fn persist_initial_head() {
+    use datastore::basic_ds::new_map_datastore;
+    use plum_api_client::{HeadChange, HeadChangeType};
+
+    let chain = Arc::new(MockChain::new());
+    chain.append_n(3);
+    let (events, _listen) = Events::new(chain.clone(), executor());
+    events
+        .write()
+        .unwrap()
+        .restore(Box::new(new_map_datastore()))
+        .unwrap();
+    let current = HeadChange {
+        r#type: HeadChangeType::Current,
+        val: chain.head(),
+    };
+    events.write().unwrap().replay(vec![vec![current]]).unwrap();
+    let store = events.write().unwrap().take_store().unwrap();
+
+    // only the initial head is cached, and it's restored
+    let (restored, _listen) = Events::new(chain.clone(), executor());
+    restored.write().unwrap().restore(store).unwrap();
+    let (_, applied, _) = chain_at(&restored, 0, 3);
+    assert_eq!(*applied.borrow(), vec![3]);
+}
@@ -0,0 +1,369 @@
+// Copyright 2020 PolkaX
+
+//! An in-memory chain for tests, which implements the `ChainApi` and the
+//! miner-facing `StateApi` calls of `plum_api_client`, so components using a
+//! full node (e.g. `events`, which gets its `EventsApi` from them) could run
+//! offline.
+//!
+//! Tests could append tipsets, produce null rounds and trigger reorgs of any
+//! depth, all head changes are sent to the `chain_notify` subscribers. The
+//! state of the only miner `MOCK_MINER` is set by `set_miner`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use cid::{Cid, Codec, IntoExt};
+use futures::channel::mpsc;
+
+use plum_address::Address;
+use plum_api_client::{
+    BlockMessages, ChainApi, ChainSectorInfo, ClientError, HeadChange, HeadChangeType, MinerPower,
+    NotificationStream, Result, StateApi, SubscriptionId,
+};
+use plum_bigint::BigInt;
+use plum_block::{BlockHeader, EPostProof, Ticket};
+use plum_crypto::Signature;
+use plum_message::UnsignedMessage;
+use plum_tipset::{Tipset, TipsetKey};
+use plum_vm::MessageReceipt;
+
+/// Seconds between two epochs, only used for block timestamps.
+pub const BLOCK_DELAY: u64 = 45;
+/// Id of the miner mining all mock blocks.
+pub const MOCK_MINER: u64 = 1000;
+
+fn dummy_cid(data: &[u8]) -> Cid {
+    Cid::new_v1(Codec::DagCBOR, multihash::Sha2_256::digest(data).into_ext())
+}
+
+/// Create a tipset with a single block on top of `parent`. Blocks with
+/// different `nonce` are different, which is used to create forks.
+pub fn new_tipset(parent: Option<&Tipset>, height: u64, nonce: u64) -> Tipset {
+    let header = BlockHeader {
+        miner: Address::new_id_addr(MOCK_MINER).expect("id address must be valid"),
+        ticket: Ticket {
+            vrf_proof: nonce.to_be_bytes().to_vec(),
+        },
+        epost_proof: EPostProof::default(),
+        parents: parent.map(|p| p.cids().to_vec()).unwrap_or_default(),
+        parent_weight: BigInt::from(height),
+        height,
+        parent_state_root: dummy_cid(b"state"),
+        parent_message_receipts: dummy_cid(b"receipts"),
+        messages: dummy_cid(&nonce.to_be_bytes()),
+        bls_aggregate: Signature::new_bls(vec![]),
+        timestamp: height * BLOCK_DELAY,
+        block_sig: None,
+        fork_signaling: 0,
+    };
+    Tipset::new(vec![header]).expect("mock tipset must be valid")
+}
+
+/// A message from the mock miner, messages with different `nonce` are
+/// different.
+pub fn new_message(nonce: u64) -> UnsignedMessage {
+    let miner = Address::new_id_addr(MOCK_MINER).expect("id address must be valid");
+    UnsignedMessage {
+        to: miner.clone(),
+        from: miner,
+        nonce,
+        value: BigInt::from(0),
+        gas_price: BigInt::from(0),
+        gas_limit: BigInt::from(0),
+        method: 0,
+        params: vec![],
+    }
+}
+
+/// A receipt of a message executed with `exit_code`.
+pub fn new_receipt(exit_code: u8) -> MessageReceipt {
+    MessageReceipt {
+        exit_code,
+        return_data: vec![],
+        gas_used: BigInt::from(0),
+    }
+}
+
+fn not_found(what: String) -> ClientError {
+    ClientError::Other(format!("mock chain: {} not found", what))
+}
+
+/// State of the mock miner returned by the `StateApi` calls.
+#[derive(Clone, Debug)]
+pub struct MockMiner {
+    pub power: BigInt,
+    pub total_power: BigInt,
+    pub sector_size: u64,
+    pub proving_set: Vec<ChainSectorInfo>,
+}
+
+impl Default for MockMiner {
+    fn default() -> Self {
+        MockMiner {
+            power: BigInt::from(0),
+            total_power: BigInt::from(0),
+            sector_size: 2048,
+            proving_set: vec![],
+        }
+    }
+}
+
+struct ChainInner {
+    // canonical chain from genesis to head, null rounds are skipped
+    chain: Vec<Tipset>,
+    // every tipset ever created (including reverted forks), by key
+    tipsets: HashMap<Vec<Cid>, Tipset>,
+    // block cid -> messages included in the block
+    block_messages: HashMap<Cid, Vec<UnsignedMessage>>,
+    receipts: HashMap<Cid, MessageReceipt>,
+    // messages included in the next appended tipset
+    pending: Vec<UnsignedMessage>,
+    subscribers: Vec<mpsc::UnboundedSender<Vec<HeadChange>>>,
+    nonce: u64,
+    miner: MockMiner,
+}
+
+impl ChainInner {
+    fn head(&self) -> &Tipset {
+        self.chain.last().expect("genesis must exist")
+    }
+
+    fn tipset(&self, key: &TipsetKey) -> Result<Tipset> {
+        self.tipsets
+            .get(key.cids())
+            .cloned()
+            .ok_or_else(|| not_found(format!("tipset {:?}", key)))
+    }
+
+    fn miner(&self, addr: &Address) -> Result<&MockMiner> {
+        if *addr != Address::new_id_addr(MOCK_MINER).expect("id address must be valid") {
+            return Err(not_found(format!("miner {}", addr)));
+        }
+        Ok(&self.miner)
+    }
+
+    fn new_tipset(&mut self, height: u64) -> Tipset {
+        self.nonce += 1;
+        let ts = new_tipset(Some(self.head()), height, self.nonce);
+        let msgs = std::mem::replace(&mut self.pending, vec![]);
+        self.block_messages.insert(ts.cids()[0].clone(), msgs);
+        self.tipsets.insert(ts.cids().to_vec(), ts.clone());
+        self.chain.push(ts.clone());
+        ts
+    }
+
+    fn notify(&mut self, changes: Vec<HeadChange>) {
+        self.subscribers
+            .retain(|s| s.unbounded_send(changes.clone()).is_ok());
+    }
+}
+
+pub struct MockChain {
+    inner: Mutex<ChainInner>,
+}
+
+impl Default for MockChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockChain {
+    /// Create a chain with only the genesis tipset at height 0.
+    pub fn new() -> Self {
+        let genesis = new_tipset(None, 0, 0);
+        let mut tipsets = HashMap::new();
+        tipsets.insert(genesis.cids().to_vec(), genesis.clone());
+        MockChain {
+            inner: Mutex::new(ChainInner {
+                chain: vec![genesis],
+                tipsets,
+                block_messages: Default::default(),
+                receipts: Default::default(),
+                pending: vec![],
+                subscribers: vec![],
+                nonce: 0,
+                miner: MockMiner::default(),
+            }),
+        }
+    }
+
+    pub fn head(&self) -> Tipset {
+        self.inner.lock().unwrap().head().clone()
+    }
+
+    /// The tipset at `height` on the current chain, `None` for null rounds.
+    pub fn tipset_at(&self, height: u64) -> Option<Tipset> {
+        let inner = self.inner.lock().unwrap();
+        inner.chain.iter().find(|ts| ts.height() == height).cloned()
+    }
+
+    /// Include `msg` into the next appended tipset, `receipt` is returned
+    /// by `state_get_receipt` for it.
+    pub fn push_message(&self, msg: UnsignedMessage, receipt: MessageReceipt) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.receipts.insert(msg.cid(), receipt);
+        inner.pending.push(msg);
+    }
+
+    /// Append a tipset at the next height.
+    pub fn append(&self) -> Tipset {
+        self.append_after_null_rounds(0)
+    }
+
+    pub fn append_n(&self, n: usize) -> Vec<Tipset> {
+        (0..n).map(|_| self.append()).collect()
+    }
+
+    /// Skip `null_rounds` heights and append a tipset after them.
+    pub fn append_after_null_rounds(&self, null_rounds: u64) -> Tipset {
+        let mut inner = self.inner.lock().unwrap();
+        let height = inner.head().height() + null_rounds + 1;
+        let ts = inner.new_tipset(height);
+        inner.notify(vec![HeadChange {
+            r#type: HeadChangeType::Apply,
+            val: ts.clone(),
+        }]);
+        ts
+    }
+
+    /// Revert `depth` tipsets from the head and apply `len` new tipsets on
+    /// the fork, the head changes are sent in one notification.
+    /// Return the reverted (from head downwards) and applied tipsets.
+    pub fn reorg(&self, depth: usize, len: usize) -> (Vec<Tipset>, Vec<Tipset>) {
+        let mut inner = self.inner.lock().unwrap();
+        assert!(depth < inner.chain.len(), "could not revert genesis");
+
+        let keep = inner.chain.len() - depth;
+        let mut reverts = inner.chain.split_off(keep);
+        reverts.reverse();
+        let applies = (0..len)
+            .map(|_| {
+                let height = inner.head().height() + 1;
+                inner.new_tipset(height)
+            })
+            .collect::<Vec<_>>();
+
+        let changes = reverts
+            .iter()
+            .map(|ts| HeadChange {
+                r#type: HeadChangeType::Revert,
+                val: ts.clone(),
+            })
+            .chain(applies.iter().map(|ts| HeadChange {
+                r#type: HeadChangeType::Apply,
+                val: ts.clone(),
+            }))
+            .collect();
+        inner.notify(changes);
+        (reverts, applies)
+    }
+
+    /// Set the state of `MOCK_MINER`.
+    pub fn set_miner(&self, miner: MockMiner) {
+        self.inner.lock().unwrap().miner = miner;
+    }
+
+    /// Close all `chain_notify` streams, as if the connection was lost.
+    pub fn disconnect(&self) {
+        self.inner.lock().unwrap().subscribers.clear();
+    }
+}
+
+#[async_trait::async_trait]
+impl ChainApi for MockChain {
+    async fn chain_notify(&self) -> Result<(SubscriptionId, NotificationStream<Vec<HeadChange>>)> {
+        let mut inner = self.inner.lock().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        let current = HeadChange {
+            r#type: HeadChangeType::Current,
+            val: inner.head().clone(),
+        };
+        let _ = tx.unbounded_send(vec![current]);
+        inner.subscribers.push(tx);
+        let id = SubscriptionId::Num(inner.subscribers.len() as u64);
+        Ok((id.clone(), NotificationStream::new(id, rx)))
+    }
+
+    async fn chain_head(&self) -> Result<Tipset> {
+        Ok(self.head())
+    }
+
+    async fn chain_get_tipset(&self, key: &TipsetKey) -> Result<Tipset> {
+        let inner = self.inner.lock().unwrap();
+        inner.tipset(key)
+    }
+
+    async fn chain_get_tipset_by_height(&self, height: u64, key: &TipsetKey) -> Result<Tipset> {
+        let inner = self.inner.lock().unwrap();
+        let mut ts = if key.cids().is_empty() {
+            inner.head().clone()
+        } else {
+            inner.tipset(key)?
+        };
+        if ts.height() < height {
+            return Err(not_found(format!("tipset at height {}", height)));
+        }
+        while ts.height() > height {
+            ts = inner.tipset(ts.parents())?;
+        }
+        Ok(ts)
+    }
+
+    async fn chain_get_block_messages(&self, block: &Cid) -> Result<BlockMessages> {
+        let inner = self.inner.lock().unwrap();
+        Ok(BlockMessages {
+            bls_messages: inner.block_messages.get(block).cloned().unwrap_or_default(),
+            secpk_messages: vec![],
+            cids: vec![],
+        })
+    }
+
+    /// The randomness is derived from `round` only, so it's the same on
+    /// all forks.
+    async fn chain_get_randomness(&self, _key: &TipsetKey, round: i64) -> Result<Vec<u8>> {
+        Ok(multihash::Sha2_256::digest(&round.to_be_bytes())
+            .digest()
+            .to_vec())
+    }
+
+    async fn chain_tipset_weight(&self, key: &TipsetKey) -> Result<BigInt> {
+        let inner = self.inner.lock().unwrap();
+        Ok(BigInt::from(inner.tipset(key)?.height()))
+    }
+}
+
+#[async_trait::async_trait]
+impl StateApi for MockChain {
+    async fn state_get_receipt(&self, msg: &Cid, _key: &TipsetKey) -> Result<MessageReceipt> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .receipts
+            .get(msg)
+            .cloned()
+            .ok_or_else(|| not_found(format!("receipt of {}", msg)))
+    }
+
+    async fn state_miner_power(&self, addr: &Address, _key: &TipsetKey) -> Result<MinerPower> {
+        let inner = self.inner.lock().unwrap();
+        let miner = inner.miner(addr)?;
+        Ok(MinerPower {
+            miner_power: miner.power.clone(),
+            total_power: miner.total_power.clone(),
+        })
+    }
+
+    async fn state_miner_sector_size(&self, addr: &Address, _key: &TipsetKey) -> Result<u64> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.miner(addr)?.sector_size)
+    }
+
+    async fn state_miner_proving_set(
+        &self,
+        addr: &Address,
+        _key: &TipsetKey,
+    ) -> Result<Vec<ChainSectorInfo>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.miner(addr)?.proving_set.clone())
+    }
+}
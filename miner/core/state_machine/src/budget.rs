@@ -0,0 +1,50 @@
+// Copyright 2020 PolkaX
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::SectorState;
+
+/// Longest time a sector should stay in a state, a sector over the budget
+/// of its state is stuck, e.g. waiting for a message which never lands.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateBudgets {
+    budgets: HashMap<SectorState, Duration>,
+    /// move stuck sectors to the failure state of their state, so they are
+    /// retried by the `RetryPolicy`, instead of only marking them stuck
+    pub retry: bool,
+}
+
+impl Default for StateBudgets {
+    /// Budgets of the states waiting for messages, only marking sectors.
+    fn default() -> Self {
+        let mut budgets = StateBudgets::none();
+        let six_hours = Duration::from_secs(6 * 3600);
+        budgets
+            .set(SectorState::WaitSeed, six_hours)
+            .set(SectorState::CommitWait, six_hours)
+            .set(SectorState::RecoveryWait, six_hours)
+            .set(SectorState::TerminateWait, six_hours);
+        budgets
+    }
+}
+
+impl StateBudgets {
+    /// No budget, sectors are never stuck.
+    pub fn none() -> Self {
+        StateBudgets {
+            budgets: HashMap::new(),
+            retry: false,
+        }
+    }
+
+    /// Set the budget of `state`.
+    pub fn set(&mut self, state: SectorState, budget: Duration) -> &mut Self {
+        self.budgets.insert(state, budget);
+        self
+    }
+
+    pub fn get(&self, state: &SectorState) -> Option<Duration> {
+        self.budgets.get(state).copied()
+    }
+}
@@ -1,9 +1,34 @@
 // Copyright 2020 PolkaX
 
-use crate::{Handler, Piece, SectorInfo, StateMachine};
+use std::time::Duration;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct EventError {}
+use thiserror::Error;
+
+use crate::{Handler, Piece, SectorInfo, SectorState, StateMachine};
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum EventError {
+    /// The event would make a transition not in `TRANSITIONS`.
+    #[error("event {event} is not allowed in state {state:?}")]
+    IllegalEvent {
+        event: &'static str,
+        state: SectorState,
+    },
+    #[error("unknown sector state {0}")]
+    UnknownState(String),
+    #[error("unknown sector {0}")]
+    UnknownSector(u64),
+    /// A manual state update to a state which needs a field the sector
+    /// doesn't have yet.
+    #[error("sector state {state:?} needs {field}")]
+    MissingField {
+        state: SectorState,
+        field: &'static str,
+    },
+    /// A chain call made by the event failed.
+    #[error("sealing api: {0}")]
+    Api(String),
+}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum EventRet {
@@ -13,14 +38,78 @@ pub enum EventRet {
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct SectorStart {
-    id: u64,
-    pieces: Vec<Piece>,
+    pub id: u64,
+    pub pieces: Vec<Piece>,
+}
+
+/// Force a sector into `state`, for error recovery.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateUpdate {
+    pub id: u64,
+    pub state: SectorState,
+    pub reason: String,
+}
+
+/// A proving sector which could not be proven any more.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SectorFault {
+    pub id: u64,
+    /// chain epoch the fault is detected at
+    pub epoch: u64,
+}
+
+/// The seed epoch of a sector got enough confidence, or is reverted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeedEpoch {
+    pub id: u64,
+    pub epoch: u64,
+}
+
+/// The sector stayed in `state` longer than its budget.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StuckSector {
+    pub id: u64,
+    pub state: SectorState,
+    /// time in `state` when it's found stuck
+    pub elapsed: Duration,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum EventType {
     Exit,
     Packing(SectorStart),
+    UpdateState(StateUpdate),
+    FaultDetected(SectorFault),
+    /// The faulty sector could be proven again.
+    FaultRecovered(u64),
+    /// The sector is faulty for longer than `FAULT_MAX_AGE`.
+    FaultExpired(u64),
+    SeedReady(SeedEpoch),
+    SeedReverted(SeedEpoch),
+    /// Terminate the proving sector on chain, and remove it.
+    Terminate(u64),
+    /// Remove the files and the saved info of the failed sector.
+    Remove(u64),
+    Stuck(StuckSector),
+}
+
+impl EventType {
+    /// Name of the event, without its data.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventType::Exit => "Exit",
+            EventType::Packing(_) => "Packing",
+            EventType::UpdateState(_) => "UpdateState",
+            EventType::FaultDetected(_) => "FaultDetected",
+            EventType::FaultRecovered(_) => "FaultRecovered",
+            EventType::FaultExpired(_) => "FaultExpired",
+            EventType::SeedReady(_) => "SeedReady",
+            EventType::SeedReverted(_) => "SeedReverted",
+            EventType::Terminate(_) => "Terminate",
+            EventType::Remove(_) => "Remove",
+            EventType::Stuck(_) => "Stuck",
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -32,6 +121,34 @@ impl Event {
     pub fn new(event_type: EventType) -> Self {
         Event { event_type }
     }
+
+    pub fn event_type(&self) -> &EventType {
+        &self.event_type
+    }
+
+    /// The sector this event is for, `None` for events of all sectors.
+    pub fn sector_id(&self) -> Option<u64> {
+        match &self.event_type {
+            EventType::Exit => None,
+            EventType::Packing(sector_start) => Some(sector_start.id),
+            EventType::UpdateState(update) => Some(update.id),
+            EventType::FaultDetected(fault) => Some(fault.id),
+            EventType::SeedReady(seed) | EventType::SeedReverted(seed) => Some(seed.id),
+            EventType::Stuck(stuck) => Some(stuck.id),
+            EventType::FaultRecovered(id)
+            | EventType::FaultExpired(id)
+            | EventType::Terminate(id)
+            | EventType::Remove(id) => Some(*id),
+        }
+    }
+
+    /// Why the event is sent, for events given by the operator.
+    pub fn reason(&self) -> Option<&str> {
+        match &self.event_type {
+            EventType::UpdateState(update) => Some(&update.reason),
+            _ => None,
+        }
+    }
 }
 
 impl Handler for Event {
@@ -39,7 +156,43 @@ impl Handler for Event {
         match &self.event_type {
             EventType::Exit => Ok(EventRet::Exit),
             EventType::Packing(sector_start) => {
-                state_machine.handle_packing(sector_start);
+                state_machine.handle_packing(sector_start)?;
+                Ok(EventRet::OK)
+            }
+            EventType::UpdateState(update) => {
+                state_machine.handle_update_state(update)?;
+                Ok(EventRet::OK)
+            }
+            EventType::FaultDetected(fault) => {
+                state_machine.handle_fault_detected(fault)?;
+                Ok(EventRet::OK)
+            }
+            EventType::FaultRecovered(_) => {
+                state_machine.handle_fault_recovered()?;
+                Ok(EventRet::OK)
+            }
+            EventType::FaultExpired(_) => {
+                state_machine.handle_fault_expired()?;
+                Ok(EventRet::OK)
+            }
+            EventType::SeedReady(seed) => {
+                state_machine.handle_seed_ready(seed)?;
+                Ok(EventRet::OK)
+            }
+            EventType::SeedReverted(seed) => {
+                state_machine.handle_seed_reverted(seed)?;
+                Ok(EventRet::OK)
+            }
+            EventType::Terminate(_) => {
+                state_machine.handle_terminate()?;
+                Ok(EventRet::OK)
+            }
+            EventType::Remove(_) => {
+                state_machine.handle_remove()?;
+                Ok(EventRet::OK)
+            }
+            EventType::Stuck(stuck) => {
+                state_machine.handle_stuck(stuck)?;
                 Ok(EventRet::OK)
             }
         }
@@ -0,0 +1,488 @@
+// Copyright 2020 PolkaX
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use log::{error, info, warn};
+
+use crate::event::{SectorFault, StateUpdate, StuckSector};
+use crate::history::unix_time;
+use crate::sealing::FAULT_MAX_AGE;
+use crate::store::force_state;
+use crate::{
+    is_allowed, Event, EventError, EventType, Planner, SectorBuilder, SectorInfo, SectorLogEntry,
+    SectorState, StateThread, TransitionObserver, TARGET,
+};
+
+/// How long to wait for the state machine of a sector in a terminal state
+/// to stop.
+const REAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Manager of the state machines of all sectors, each sector runs its own
+/// state machine thread, so sectors are sealed concurrently.
+pub struct StateGroup {
+    sb: Arc<SectorBuilder>,
+    sectors: Mutex<Sectors>,
+}
+
+/// The state machine threads of sectors, and the events sent to sectors
+/// whose state machines are being stopped.
+#[derive(Default)]
+struct Sectors {
+    running: BTreeMap<u64, StateThread>,
+    stopping: BTreeMap<u64, Vec<Event>>,
+}
+
+impl Sectors {
+    fn contains(&self, sector_id: u64) -> bool {
+        self.running.contains_key(&sector_id) || self.stopping.contains_key(&sector_id)
+    }
+}
+
+impl StateGroup {
+    pub fn new(sb: SectorBuilder) -> Self {
+        StateGroup {
+            sb: Arc::new(sb),
+            sectors: Mutex::new(Sectors::default()),
+        }
+    }
+
+    /// Resume the state machines of all saved sectors which are not in a
+    /// terminal state, return the number of resumed sectors.
+    pub fn restore(&self) -> Result<usize> {
+        let store = match self.sb.store.as_ref() {
+            Some(store) => store,
+            None => return Ok(0),
+        };
+        let mut sectors = self.sectors.lock().unwrap();
+        let infos = store
+            .list()?
+            .into_iter()
+            .filter(|info| !info.state.is_terminal() && !sectors.contains(info.sector_id))
+            .collect::<Vec<_>>();
+        // count all resumed sectors in the sealing limits before any of them
+        // moves, for the queued transitions
+        for info in infos.iter() {
+            if info.state != SectorState::Empty {
+                self.sb.limiter.update(info.sector_id, &info.state);
+            }
+        }
+        let mut resumed = 0;
+        for info in infos {
+            info!(
+                target: TARGET,
+                "resume sector {} in {:?}", info.sector_id, info.state
+            );
+            let sector_id = info.sector_id;
+            sectors
+                .running
+                .insert(sector_id, StateThread::run(self.sb.clone(), info));
+            resumed += 1;
+        }
+        Ok(resumed)
+    }
+
+    /// Send `event` to the state machine of `sector_id`. A state machine is
+    /// started for a new sector by its `Packing` event, or for a saved sector
+    /// without a running state machine, other events of unknown sectors are
+    /// dropped.
+    pub fn send(&self, sector_id: u64, event: Event) {
+        self.reap();
+        let mut sectors = self.sectors.lock().unwrap();
+        self.deliver(&mut sectors, sector_id, event);
+    }
+
+    fn deliver(&self, sectors: &mut Sectors, sector_id: u64, event: Event) {
+        if let Some(events) = sectors.stopping.get_mut(&sector_id) {
+            // handled once the state machine is stopped
+            events.push(event);
+            return;
+        }
+        let stopped = sectors
+            .running
+            .get(&sector_id)
+            .map(StateThread::is_stopped)
+            .unwrap_or(false);
+        if stopped {
+            // e.g. by `Exit`, resumed from where it stopped
+            let mut thread = sectors.running.remove(&sector_id).expect("checked above");
+            thread.join(Duration::from_secs(0));
+            let thread = StateThread::run(self.sb.clone(), thread.info());
+            sectors.running.insert(sector_id, thread);
+        }
+        if !sectors.running.contains_key(&sector_id) {
+            let info = match self.load(sector_id) {
+                Some(info) => info,
+                None => match event.event_type() {
+                    EventType::Packing(_) => SectorInfo::new(),
+                    _ => {
+                        warn!(
+                            target: TARGET,
+                            "drop event {:?} of unknown sector {}", event, sector_id
+                        );
+                        return;
+                    }
+                },
+            };
+            let thread = StateThread::run(self.sb.clone(), info);
+            sectors.running.insert(sector_id, thread);
+        }
+        sectors.running[&sector_id].plan(&[event]);
+    }
+
+    /// Sectors with a running state machine, after stopping the ones in
+    /// terminal states.
+    pub fn running(&self) -> Vec<u64> {
+        self.reap();
+        let sectors = self.sectors.lock().unwrap();
+        sectors
+            .running
+            .iter()
+            .filter(|(_, thread)| !thread.is_stopped())
+            .map(|(sector_id, _)| *sector_id)
+            .collect()
+    }
+
+    /// Stop the state machines of sectors in terminal states, which only
+    /// wait for events, and drop the stopped state machines of saved
+    /// sectors. A saved sector gets its state machine again from the store
+    /// on its next event, removed sectors are dropped.
+    fn reap(&self) {
+        let has_store = self.sb.store.is_some();
+        let mut stopping = vec![];
+        {
+            let mut sectors = self.sectors.lock().unwrap();
+            let Sectors {
+                running,
+                stopping: events,
+            } = &mut *sectors;
+            let done = running
+                .iter()
+                .filter(|(_, thread)| {
+                    let state = thread.state();
+                    state == SectorState::Removed
+                        || (has_store && (state.is_terminal() || thread.is_stopped()))
+                })
+                .map(|(sector_id, _)| *sector_id)
+                .collect::<Vec<_>>();
+            for sector_id in done {
+                let mut thread = running.remove(&sector_id).expect("listed above");
+                if thread.is_stopped() {
+                    thread.join(Duration::from_secs(0));
+                    continue;
+                }
+                thread.shutdown();
+                events.insert(sector_id, vec![]);
+                stopping.push((sector_id, thread));
+            }
+        }
+        if stopping.is_empty() {
+            return;
+        }
+
+        // joined without the lock, so that other sectors are not blocked
+        let deadline = Instant::now() + REAP_TIMEOUT;
+        let joined = stopping
+            .into_iter()
+            .map(|(sector_id, mut thread)| {
+                let stopped = thread.join(deadline.saturating_duration_since(Instant::now()));
+                (sector_id, thread, stopped)
+            })
+            .collect::<Vec<_>>();
+
+        let mut sectors = self.sectors.lock().unwrap();
+        for (sector_id, thread, stopped) in joined {
+            let events = sectors.stopping.remove(&sector_id).unwrap_or_default();
+            if !stopped {
+                warn!(
+                    target: TARGET,
+                    "state machine of sector {} is not stopped in {:?}", sector_id, REAP_TIMEOUT
+                );
+                sectors.running.insert(sector_id, thread);
+            } else {
+                // events applied while stopping may have moved it on
+                let info = thread.info();
+                if !info.state.is_terminal() {
+                    let thread = StateThread::run(self.sb.clone(), info);
+                    sectors.running.insert(sector_id, thread);
+                }
+            }
+            for event in events {
+                self.deliver(&mut sectors, sector_id, event);
+            }
+        }
+    }
+
+    /// The current info of the sector, `None` for unknown sectors.
+    pub fn state(&self, sector_id: u64) -> Option<SectorInfo> {
+        let sectors = self.sectors.lock().unwrap();
+        match sectors.running.get(&sector_id) {
+            Some(thread) => Some(thread.info()),
+            None => self.load(sector_id),
+        }
+    }
+
+    /// The current info of all sectors, including saved sectors without a
+    /// running state machine, ordered by sector number.
+    pub fn sectors(&self) -> Vec<SectorInfo> {
+        let sectors = self.sectors.lock().unwrap();
+        let mut infos: BTreeMap<u64, SectorInfo> = self
+            .sb
+            .store
+            .as_ref()
+            .and_then(|store| match store.list() {
+                Ok(list) => Some(list),
+                Err(e) => {
+                    error!(target: TARGET, "listing saved sectors failed: {:?}", e);
+                    None
+                }
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .map(|info| (info.sector_id, info))
+            .collect();
+        for (sector_id, thread) in sectors.running.iter() {
+            infos.insert(*sector_id, thread.info());
+        }
+        infos.into_iter().map(|(_, info)| info).collect()
+    }
+
+    /// Force the sector into `state` for error recovery, with `reason` kept
+    /// in its event log. The state machine of a running sector applies the
+    /// update in its own thread.
+    pub fn update_state(&self, sector_id: u64, state: SectorState, reason: &str) -> Result<()> {
+        self.reap();
+        let mut sectors = self.sectors.lock().unwrap();
+        if sectors.contains(sector_id) {
+            let info = match sectors.running.get(&sector_id) {
+                Some(thread) => thread.info(),
+                None => self
+                    .load(sector_id)
+                    .ok_or(EventError::UnknownSector(sector_id))?,
+            };
+            info.check_state(&state)?;
+            let update = StateUpdate {
+                id: sector_id,
+                state,
+                reason: reason.to_string(),
+            };
+            self.deliver(
+                &mut sectors,
+                sector_id,
+                Event::new(EventType::UpdateState(update)),
+            );
+            return Ok(());
+        }
+        let store = self
+            .sb
+            .store
+            .as_ref()
+            .ok_or(EventError::UnknownSector(sector_id))?;
+        force_state(
+            store.as_ref(),
+            &self.sb.observers,
+            sector_id,
+            state.clone(),
+            reason,
+        )?;
+        // resume the sector to do the work of the new state
+        if !state.is_terminal() {
+            if let Some(info) = self.load(sector_id) {
+                let thread = StateThread::run(self.sb.clone(), info);
+                sectors.running.insert(sector_id, thread);
+            }
+        }
+        Ok(())
+    }
+
+    /// Terminate the proving sector on chain, and remove it after the
+    /// termination landed.
+    pub fn terminate(&self, sector_id: u64) -> Result<()> {
+        self.request(
+            sector_id,
+            EventType::Terminate(sector_id),
+            SectorState::Terminating,
+        )
+    }
+
+    /// Remove the files and the saved info of the failed sector.
+    pub fn remove(&self, sector_id: u64) -> Result<()> {
+        self.request(
+            sector_id,
+            EventType::Remove(sector_id),
+            SectorState::Removing,
+        )
+    }
+
+    /// Send `event` which moves the sector to `to`, a state machine is
+    /// started for a saved sector without one.
+    fn request(&self, sector_id: u64, event: EventType, to: SectorState) -> Result<()> {
+        let state = match self.state(sector_id) {
+            Some(info) => info.state,
+            None => return Err(EventError::UnknownSector(sector_id).into()),
+        };
+        if !is_allowed(&state, &to) {
+            return Err(EventError::IllegalEvent {
+                event: event.name(),
+                state,
+            }
+            .into());
+        }
+        self.send(sector_id, Event::new(event));
+        Ok(())
+    }
+
+    /// Check whether the proving and faulty sectors could be proven at the
+    /// chain epoch `epoch`, and send them the fault events: unprovable
+    /// proving sectors become faulty, reported faults are recovered once the
+    /// sector could be proven again, or expire after `FAULT_MAX_AGE`.
+    pub fn check_faults(&self, epoch: u64) {
+        for info in self.sectors() {
+            let sector_id = info.sector_id;
+            let event = match info.state {
+                SectorState::Proving | SectorState::FaultReported => {
+                    match self.sb.api.check_provable(&info) {
+                        Ok(provable) => match (&info.state, provable) {
+                            (SectorState::Proving, false) => {
+                                EventType::FaultDetected(SectorFault {
+                                    id: sector_id,
+                                    epoch,
+                                })
+                            }
+                            (SectorState::FaultReported, true) => {
+                                EventType::FaultRecovered(sector_id)
+                            }
+                            (SectorState::FaultReported, false)
+                                if epoch > info.fault_epoch + FAULT_MAX_AGE =>
+                            {
+                                EventType::FaultExpired(sector_id)
+                            }
+                            _ => continue,
+                        },
+                        Err(e) => {
+                            error!(
+                                target: TARGET,
+                                "checking sector {} failed: {:?}", sector_id, e
+                            );
+                            continue;
+                        }
+                    }
+                }
+                _ => continue,
+            };
+            self.send(sector_id, Event::new(event));
+        }
+    }
+
+    /// Send `Stuck` to the sectors which are in their state longer than its
+    /// budget in `StateBudgets` at `now`, return the sectors newly found
+    /// stuck. Sectors already marked stuck are skipped until they move on.
+    pub fn check_stuck(&self, now: SystemTime) -> Vec<u64> {
+        let now = unix_time(now);
+        let mut stuck = vec![];
+        for info in self.sectors() {
+            // the entry time of old saved sectors is unknown until their
+            // state machine starts
+            if info.stuck || info.state_entered == 0 {
+                continue;
+            }
+            let budget = match self.sb.state_budgets.get(&info.state) {
+                Some(budget) => budget,
+                None => continue,
+            };
+            let elapsed = Duration::from_secs(now.saturating_sub(info.state_entered));
+            if elapsed <= budget {
+                continue;
+            }
+            let event = EventType::Stuck(StuckSector {
+                id: info.sector_id,
+                state: info.state,
+                elapsed,
+            });
+            self.send(info.sector_id, Event::new(event));
+            stuck.push(info.sector_id);
+        }
+        stuck
+    }
+
+    /// Stop all state machines after their current work, and wait at most
+    /// `timeout` for them, return the sectors whose state machines are still
+    /// running. The stopped state machines are dropped, saved sectors get
+    /// them again on their next events.
+    pub fn shutdown(&self, timeout: Duration) -> Vec<u64> {
+        let mut sectors = self.sectors.lock().unwrap();
+        for thread in sectors.running.values() {
+            thread.shutdown();
+        }
+        let deadline = Instant::now() + timeout;
+        let mut running = vec![];
+        for (sector_id, thread) in sectors.running.iter_mut() {
+            if !thread.join(deadline.saturating_duration_since(Instant::now())) {
+                running.push(*sector_id);
+            }
+        }
+        sectors
+            .running
+            .retain(|sector_id, _| running.contains(sector_id));
+        running
+    }
+
+    /// Number of sectors queued by the sealing limits, by the state they
+    /// are waiting to enter.
+    pub fn queued(&self) -> BTreeMap<SectorState, usize> {
+        self.sb.limiter.queued()
+    }
+
+    /// Number of sectors in each state, of the sectors with a state machine
+    /// since start which are not removed.
+    pub fn state_counts(&self) -> BTreeMap<SectorState, usize> {
+        self.sb.limiter.counts()
+    }
+
+    /// Notify `observer` of every transition from now on.
+    pub fn add_observer(&self, observer: Arc<dyn TransitionObserver>) {
+        self.sb.observers.add(observer);
+    }
+
+    /// The event log of the sector, empty if sectors are not saved.
+    pub fn log(&self, sector_id: u64) -> Result<Vec<SectorLogEntry>> {
+        match self.sb.store.as_ref() {
+            Some(store) => store.log(sector_id),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn load(&self, sector_id: u64) -> Option<SectorInfo> {
+        let store = self.sb.store.as_ref()?;
+        match store.load(sector_id) {
+            Ok(info) => info,
+            Err(e) => {
+                error!(
+                    target: TARGET,
+                    "loading sector {} failed: {:?}", sector_id, e
+                );
+                None
+            }
+        }
+    }
+}
+
+impl Planner for StateGroup {
+    /// Route every event to the state machine of its sector, events without
+    /// a sector (e.g. `Exit`) are sent to all state machines.
+    fn plan(&self, events: &[Event]) {
+        for event in events {
+            match event.sector_id() {
+                Some(sector_id) => self.send(sector_id, event.clone()),
+                None => {
+                    let sectors = self.sectors.lock().unwrap();
+                    for thread in sectors.running.values() {
+                        thread.plan(&[event.clone()]);
+                    }
+                }
+            }
+        }
+    }
+}
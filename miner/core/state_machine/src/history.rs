@@ -0,0 +1,75 @@
+// Copyright 2020 PolkaX
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::SectorState;
+
+/// Seconds since the unix epoch at `time`.
+pub(crate) fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// One entry of the event log of a sector, for an event applied to the
+/// sector or a transition made by the state machine itself.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SectorLogEntry {
+    /// seconds since the unix epoch
+    pub timestamp: u64,
+    /// name of the applied event, `None` for transitions of the state machine
+    pub event: Option<String>,
+    pub from: SectorState,
+    pub to: SectorState,
+    /// why the event or the work of `from` failed
+    pub error: Option<String>,
+    /// given by the operator for manual state updates
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl SectorLogEntry {
+    pub fn new(
+        event: Option<String>,
+        from: SectorState,
+        to: SectorState,
+        error: Option<String>,
+    ) -> Self {
+        SectorLogEntry {
+            timestamp: unix_time(SystemTime::now()),
+            event,
+            from,
+            to,
+            error,
+            reason: None,
+        }
+    }
+
+    pub fn with_reason(mut self, reason: Option<String>) -> Self {
+        self.reason = reason;
+        self
+    }
+}
+
+impl fmt::Display for SectorLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {:?} -> {:?}",
+            self.timestamp,
+            self.event.as_deref().unwrap_or("-"),
+            self.from,
+            self.to
+        )?;
+        if let Some(reason) = &self.reason {
+            write!(f, " ({})", reason)?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, ": {}", error)?;
+        }
+        Ok(())
+    }
+}
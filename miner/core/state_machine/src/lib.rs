@@ -1,22 +1,53 @@
 // Copyright 2020 PolkaX
 
+mod budget;
 mod event;
+mod group;
 mod handler;
+mod history;
+mod limits;
+mod observer;
+mod packer;
+mod retry;
+mod sealing;
 mod sector_info;
+mod seed;
 mod state;
 mod state_machine;
+mod store;
 #[cfg(test)]
 mod test;
 mod thread;
+mod transition;
 
-pub use event::{Event, EventError, EventRet, EventType};
+pub use budget::StateBudgets;
+pub use event::{
+    Event, EventError, EventRet, EventType, SectorFault, SectorStart, SeedEpoch, StateUpdate,
+    StuckSector,
+};
+pub use group::StateGroup;
 pub use handler::Handler;
-pub use sector_info::{Piece, SectorInfo};
+pub use history::SectorLogEntry;
+pub use limits::{SealingLimiter, SealingLimits};
+pub use observer::{
+    DurationHistogram, Histogram, LogObserver, TransitionObserver, TransitionObservers,
+    DURATION_BUCKETS,
+};
+pub use packer::{PackerConfig, PieceFiller, PiecePacker, PiecePlacement};
+pub use retry::{RetryPolicies, RetryPolicy};
+pub use sealing::{
+    MsgLookup, SealerFactory, SealingApi, SectorBuilder, SectorFiles, EXIT_CODE_OK, FAULT_MAX_AGE,
+    INTERACTIVE_POREP_CONFIDENCE, MAX_TICKET_AGE, PRE_COMMIT_CHALLENGE_DELAY,
+};
+pub use sector_info::{Piece, SealSeed, SealTicket, SectorInfo};
+pub use seed::{register_seed_requests, SeedRequest, SeedWatcher};
 pub use state::SectorState;
 use state_machine::StateMachine;
+pub use store::{force_state, request_transition, DsSectorStore, SectorStore};
 pub use thread::StateThread;
+pub use transition::{is_allowed, transitions_dot, transitions_mermaid, Transition, TRANSITIONS};
 
-pub struct SectorBuilder {}
+const TARGET: &'static str = "state_machine";
 
 pub trait Planner {
     fn plan(&self, events: &[Event]);
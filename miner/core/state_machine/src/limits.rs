@@ -0,0 +1,140 @@
+// Copyright 2020 PolkaX
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::SectorState;
+
+/// Caps of sectors sealing at the same time, 0 means no cap. Sectors
+/// waiting for deals are capped by `PackerConfig::max_open` instead, they
+/// are open in the `PiecePacker` and get a state machine only once they are
+/// sent as `Packing`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SealingLimits {
+    /// sectors between `Packing` and `Proving`, including failed ones
+    pub max_sealing: usize,
+    /// sectors in each state
+    pub max_per_state: HashMap<SectorState, usize>,
+}
+
+impl SealingLimits {
+    pub fn set(&mut self, state: SectorState, max: usize) -> &mut Self {
+        self.max_per_state.insert(state, max);
+        self
+    }
+}
+
+fn is_sealing(state: &SectorState) -> bool {
+    matches!(
+        state,
+        SectorState::Packing
+            | SectorState::Unsealed
+            | SectorState::PreCommitting
+            | SectorState::WaitSeed
+            | SectorState::Committing
+            | SectorState::CommitWait
+            | SectorState::FinalizeSector
+            | SectorState::PackingFailed
+            | SectorState::SealFailed
+            | SectorState::PreCommitFailed
+            | SectorState::SealCommitFailed
+            | SectorState::CommitFailed
+    )
+}
+
+#[derive(Default)]
+struct Sectors {
+    states: HashMap<u64, SectorState>,
+    // sectors waiting for room in a state, by the state
+    queued: HashMap<u64, SectorState>,
+}
+
+impl Sectors {
+    fn set(&mut self, sector_id: u64, state: &SectorState) {
+        self.queued.remove(&sector_id);
+        // removed sectors are gone for good, don't count them
+        if *state == SectorState::Removed {
+            self.states.remove(&sector_id);
+        } else {
+            self.states.insert(sector_id, state.clone());
+        }
+    }
+}
+
+/// Keep the states of all sectors to enforce `SealingLimits`, a sector
+/// which could not enter a state stays in its prior state, queued.
+#[derive(Default)]
+pub struct SealingLimiter {
+    limits: SealingLimits,
+    sectors: Mutex<Sectors>,
+}
+
+impl SealingLimiter {
+    pub fn new(limits: SealingLimits) -> Self {
+        SealingLimiter {
+            limits,
+            sectors: Mutex::new(Sectors::default()),
+        }
+    }
+
+    /// Move the sector to `to` if there is room for it, or queue it.
+    pub fn try_enter(&self, sector_id: u64, to: &SectorState) -> bool {
+        let mut sectors = self.sectors.lock().unwrap();
+        let from = sectors.states.get(&sector_id);
+        if from == Some(to) {
+            return true;
+        }
+        let others = sectors
+            .states
+            .iter()
+            .filter(|(id, _)| **id != sector_id)
+            .map(|(_, state)| state);
+        let (mut sealing, mut in_state) = (0, 0);
+        for state in others {
+            if is_sealing(state) {
+                sealing += 1;
+            }
+            if state == to {
+                in_state += 1;
+            }
+        }
+        let max_in_state = self.limits.max_per_state.get(to).copied().unwrap_or(0);
+        let starts_sealing = is_sealing(to) && !from.map(is_sealing).unwrap_or(false);
+        let full = (max_in_state != 0 && in_state >= max_in_state)
+            || (starts_sealing
+                && self.limits.max_sealing != 0
+                && sealing >= self.limits.max_sealing);
+        if full {
+            sectors.queued.insert(sector_id, to.clone());
+            return false;
+        }
+        sectors.set(sector_id, to);
+        true
+    }
+
+    /// Record the state of the sector without checking the limits, for
+    /// states changed by events and manual updates.
+    pub fn update(&self, sector_id: u64, state: &SectorState) {
+        self.sectors.lock().unwrap().set(sector_id, state);
+    }
+
+    /// Number of sectors in each state.
+    pub fn counts(&self) -> BTreeMap<SectorState, usize> {
+        let sectors = self.sectors.lock().unwrap();
+        let mut counts = BTreeMap::new();
+        for state in sectors.states.values() {
+            *counts.entry(state.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Number of queued sectors, by the state they are waiting to enter.
+    pub fn queued(&self) -> BTreeMap<SectorState, usize> {
+        let sectors = self.sectors.lock().unwrap();
+        let mut queued = BTreeMap::new();
+        for state in sectors.queued.values() {
+            *queued.entry(state.clone()).or_insert(0) += 1;
+        }
+        queued
+    }
+}
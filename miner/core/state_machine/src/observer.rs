@@ -0,0 +1,136 @@
+// Copyright 2020 PolkaX
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use log::info;
+
+use crate::{SectorState, TARGET};
+
+/// Notified of every state transition of every sector, e.g. for metrics or
+/// journaling. Called on the state machine thread of the sector, so it
+/// should not block.
+pub trait TransitionObserver: Send + Sync {
+    /// The sector went from `from` to `to` on `event`, or by the work of
+    /// `from` if `None`, after `duration` in `from`.
+    fn on_transition(
+        &self,
+        sector_id: u64,
+        from: &SectorState,
+        to: &SectorState,
+        event: Option<&str>,
+        duration: Duration,
+    );
+}
+
+/// Observers registered to the state machines of one miner, observers
+/// could be added while the state machines are running.
+#[derive(Default)]
+pub struct TransitionObservers {
+    observers: RwLock<Vec<Arc<dyn TransitionObserver>>>,
+}
+
+impl TransitionObservers {
+    pub fn add(&self, observer: Arc<dyn TransitionObserver>) {
+        self.observers.write().unwrap().push(observer);
+    }
+
+    pub(crate) fn notify(
+        &self,
+        sector_id: u64,
+        from: &SectorState,
+        to: &SectorState,
+        event: Option<&str>,
+        duration: Duration,
+    ) {
+        for observer in self.observers.read().unwrap().iter() {
+            observer.on_transition(sector_id, from, to, event, duration);
+        }
+    }
+}
+
+/// Log every transition with the time spent in the old state.
+pub struct LogObserver;
+
+impl TransitionObserver for LogObserver {
+    fn on_transition(
+        &self,
+        sector_id: u64,
+        from: &SectorState,
+        to: &SectorState,
+        event: Option<&str>,
+        duration: Duration,
+    ) {
+        info!(
+            target: TARGET,
+            "sector {}: {:?} -> {:?} on {}, {:?} in {:?}",
+            sector_id,
+            from,
+            to,
+            event.unwrap_or("work done"),
+            duration,
+            from
+        );
+    }
+}
+
+/// Upper bounds of the buckets of `DurationHistogram`, in seconds, the last
+/// bucket has no upper bound.
+pub const DURATION_BUCKETS: &[u64] = &[1, 10, 60, 600, 3600, 6 * 3600, 24 * 3600];
+
+/// Durations spent in one state.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// counts of the buckets of `DURATION_BUCKETS`, and the last unbounded
+    /// bucket
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; DURATION_BUCKETS.len() + 1];
+        }
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|bound| duration <= Duration::from_secs(*bound))
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += duration;
+    }
+}
+
+/// Histogram of the time sectors spent in each state before leaving it.
+#[derive(Default)]
+pub struct DurationHistogram {
+    states: Mutex<HashMap<SectorState, Histogram>>,
+}
+
+impl DurationHistogram {
+    /// The histograms of all states left so far.
+    pub fn snapshot(&self) -> BTreeMap<SectorState, Histogram> {
+        let states = self.states.lock().unwrap();
+        states
+            .iter()
+            .map(|(state, histogram)| (state.clone(), histogram.clone()))
+            .collect()
+    }
+}
+
+impl TransitionObserver for DurationHistogram {
+    fn on_transition(
+        &self,
+        _sector_id: u64,
+        from: &SectorState,
+        _to: &SectorState,
+        _event: Option<&str>,
+        duration: Duration,
+    ) {
+        let mut states = self.states.lock().unwrap();
+        states.entry(from.clone()).or_default().observe(duration);
+    }
+}
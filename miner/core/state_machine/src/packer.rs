@@ -0,0 +1,174 @@
+// Copyright 2020 PolkaX
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use filecoin_proofs_api::UnpaddedBytesAmount;
+
+use crate::{Event, EventType, Piece, SectorStart};
+
+/// Make the filler piece of the given size, i.e. a piece of zeros.
+pub type PieceFiller = Box<dyn Fn(UnpaddedBytesAmount) -> Result<Piece> + Send + Sync>;
+
+fn padded_size(unpadded: u64) -> u64 {
+    unpadded + unpadded / 127
+}
+
+fn unpadded_size(padded: u64) -> u64 {
+    padded - padded / 128
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PackerConfig {
+    /// padded size of a sector, a power of two
+    pub sector_size: u64,
+    /// how long a sector waits for more deals before it's filled up and
+    /// sealed
+    pub max_wait: Duration,
+    /// sectors waiting for deals at the same time, the oldest one is sealed
+    /// early to open a new one, 0 means no cap
+    pub max_open: usize,
+}
+
+/// Where a piece is packed, the offset is in padded bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PiecePlacement {
+    pub sector_id: u64,
+    pub offset: u64,
+}
+
+struct OpenSector {
+    id: u64,
+    pieces: Vec<Piece>,
+    // padded bytes used, including filler pieces
+    used: u64,
+    opened: Instant,
+}
+
+/// Pack incoming deal pieces into sectors. Every piece is aligned to its
+/// padded size inside the sector, with filler pieces in between, and a
+/// sector is sealed once it's full or has waited `max_wait` for more deals,
+/// after its remaining space is filled up.
+pub struct PiecePacker {
+    config: PackerConfig,
+    filler: PieceFiller,
+    next_sector_id: u64,
+    open: Vec<OpenSector>,
+    // sectors closed early by `max_open`, sealed by the next `ready`
+    closed: Vec<OpenSector>,
+}
+
+impl PiecePacker {
+    pub fn new(config: PackerConfig, first_sector_id: u64, filler: PieceFiller) -> Self {
+        PiecePacker {
+            config,
+            filler,
+            next_sector_id: first_sector_id,
+            open: vec![],
+            closed: vec![],
+        }
+    }
+
+    /// Pack `piece` into the first open sector with room for it, or into a
+    /// new sector.
+    pub fn add_piece(&mut self, piece: Piece, now: Instant) -> Result<PiecePlacement> {
+        let size = padded_size(piece.size.0);
+        if !size.is_power_of_two() || size < 128 {
+            bail!("padded piece size {} is not a power of two", size);
+        }
+        if size > self.config.sector_size {
+            bail!(
+                "padded piece size {} is larger than sector size {}",
+                size,
+                self.config.sector_size
+            );
+        }
+
+        let sector_size = self.config.sector_size;
+        let index = match self
+            .open
+            .iter()
+            .position(|sector| align_up(sector.used, size) + size <= sector_size)
+        {
+            Some(index) => index,
+            None => {
+                if self.config.max_open != 0 && self.open.len() >= self.config.max_open {
+                    let oldest = self.open.remove(0);
+                    self.closed.push(oldest);
+                }
+                self.open.push(OpenSector {
+                    id: self.next_sector_id,
+                    pieces: vec![],
+                    used: 0,
+                    opened: now,
+                });
+                self.next_sector_id += 1;
+                self.open.len() - 1
+            }
+        };
+
+        let sector = &mut self.open[index];
+        let offset = align_up(sector.used, size);
+        fill(&self.filler, sector, offset)?;
+        sector.pieces.push(piece);
+        sector.used += size;
+        Ok(PiecePlacement {
+            sector_id: sector.id,
+            offset,
+        })
+    }
+
+    /// Take the sectors which are full, have waited long enough or are
+    /// closed early, filled up, as `Packing` events.
+    pub fn ready(&mut self, now: Instant) -> Result<Vec<Event>> {
+        let config = &self.config;
+        let (ready, open) = self.open.drain(..).partition(|sector: &OpenSector| {
+            sector.used == config.sector_size
+                || now.saturating_duration_since(sector.opened) >= config.max_wait
+        });
+        self.open = open;
+        let mut closed = std::mem::take(&mut self.closed);
+        closed.extend(ready);
+        self.seal(closed)
+    }
+
+    /// Take all open sectors, filled up, as `Packing` events.
+    pub fn flush(&mut self) -> Result<Vec<Event>> {
+        let mut closed = std::mem::take(&mut self.closed);
+        closed.append(&mut self.open);
+        self.seal(closed)
+    }
+
+    fn seal(&self, sectors: Vec<OpenSector>) -> Result<Vec<Event>> {
+        let mut events = vec![];
+        for mut sector in sectors {
+            fill(&self.filler, &mut sector, self.config.sector_size)?;
+            events.push(Event::new(EventType::Packing(SectorStart {
+                id: sector.id,
+                pieces: sector.pieces,
+            })));
+        }
+        Ok(events)
+    }
+}
+
+fn align_up(offset: u64, size: u64) -> u64 {
+    (offset + size - 1) / size * size
+}
+
+/// Fill the sector with filler pieces up to the padded offset `to`, every
+/// filler is the largest power of two aligned at its offset.
+fn fill(filler: &PieceFiller, sector: &mut OpenSector, to: u64) -> Result<()> {
+    while sector.used < to {
+        let align = 1u64 << sector.used.trailing_zeros().min(63);
+        let mut size = (to - sector.used).next_power_of_two();
+        while size > align || sector.used + size > to {
+            size /= 2;
+        }
+        sector
+            .pieces
+            .push(filler(UnpaddedBytesAmount(unpadded_size(size)))?);
+        sector.used += size;
+    }
+    Ok(())
+}
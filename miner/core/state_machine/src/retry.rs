@@ -0,0 +1,114 @@
+// Copyright 2020 PolkaX
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::SectorState;
+
+/// How a sector in a failure state is retried.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// retries before giving up into `FailedUnrecoverable`, 0 never retries
+    pub max_attempts: u32,
+    /// wait before the first retry, doubled on every further retry
+    pub backoff: Duration,
+    /// upper bound of the wait between retries
+    pub max_backoff: Duration,
+    /// the step the sector is rolled back to, which must be allowed by
+    /// `TRANSITIONS`
+    pub retry_state: SectorState,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, backoff: Duration, retry_state: SectorState) -> Self {
+        RetryPolicy {
+            max_attempts,
+            backoff,
+            max_backoff: backoff * 32,
+            retry_state,
+        }
+    }
+
+    /// Never retry, give up at once.
+    pub fn give_up() -> Self {
+        RetryPolicy::new(0, Duration::from_secs(0), SectorState::FailedUnrecoverable)
+    }
+
+    /// The wait before the retry after `attempts` retries.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        // the factor overflows u32 after 32 doublings
+        let factor = 1u32 << attempts.min(31);
+        self.backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+/// Retry policies of all failure states, states without a policy give up
+/// at once.
+#[derive(Clone, Debug)]
+pub struct RetryPolicies {
+    policies: HashMap<SectorState, RetryPolicy>,
+}
+
+impl Default for RetryPolicies {
+    /// Roll back to the failed step, as in `TRANSITIONS`.
+    fn default() -> Self {
+        let backoff = Duration::from_secs(60);
+        let mut policies = RetryPolicies {
+            policies: HashMap::new(),
+        };
+        policies
+            .set(
+                SectorState::SealFailed,
+                RetryPolicy::new(5, backoff, SectorState::Unsealed),
+            )
+            .set(
+                SectorState::PreCommitFailed,
+                RetryPolicy::new(5, backoff, SectorState::PreCommitting),
+            )
+            .set(
+                SectorState::SealCommitFailed,
+                RetryPolicy::new(5, backoff, SectorState::Committing),
+            )
+            .set(
+                SectorState::CommitFailed,
+                RetryPolicy::new(5, backoff, SectorState::Committing),
+            )
+            .set(
+                SectorState::FaultReportFailed,
+                RetryPolicy::new(5, backoff, SectorState::Faulty),
+            )
+            .set(
+                SectorState::RecoveryFailed,
+                RetryPolicy::new(5, backoff, SectorState::Recovering),
+            )
+            .set(
+                SectorState::TerminateFailed,
+                RetryPolicy::new(5, backoff, SectorState::Terminating),
+            )
+            .set(
+                SectorState::RemoveFailed,
+                RetryPolicy::new(5, backoff, SectorState::Removing),
+            )
+            // packing only fails for bad pieces, which a retry won't fix
+            .set(SectorState::PackingFailed, RetryPolicy::give_up());
+        policies
+    }
+}
+
+impl RetryPolicies {
+    /// Set the policy of the failure state `state`.
+    pub fn set(&mut self, state: SectorState, policy: RetryPolicy) -> &mut Self {
+        self.policies.insert(state, policy);
+        self
+    }
+
+    pub fn get(&self, state: &SectorState) -> RetryPolicy {
+        self.policies
+            .get(state)
+            .cloned()
+            .unwrap_or_else(RetryPolicy::give_up)
+    }
+}
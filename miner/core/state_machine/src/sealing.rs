@@ -0,0 +1,162 @@
+// Copyright 2020 PolkaX
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use cid::Cid;
+use filecoin_proofs_api::RegisteredSealProof;
+use plum_sector::SectorId;
+use specs_storage::Sealer;
+use stores::traits::Store;
+
+use crate::sector_info::{SealSeed, SealTicket};
+use crate::{
+    RetryPolicies, SealingLimiter, SealingLimits, SectorInfo, SectorStore, SeedWatcher,
+    StateBudgets, TransitionObserver, TransitionObservers,
+};
+
+/// Exit code of a successfully executed message.
+pub const EXIT_CODE_OK: i64 = 0;
+
+/// Epochs a pre-commit ticket could be drawn before the pre-commit message
+/// is included, one day of 2880 epochs and the chain finality.
+pub const MAX_TICKET_AGE: u64 = 2880 + 900;
+
+/// Epochs between the inclusion of the pre-commit message and the epoch the
+/// interactive seed is drawn at.
+pub const PRE_COMMIT_CHALLENGE_DELAY: u64 = 150;
+
+/// Epochs on top of the seed epoch before the seed is used.
+pub const INTERACTIVE_POREP_CONFIDENCE: u64 = 6;
+
+/// Epochs a sector could stay faulty before it's terminated, 14 proving
+/// periods of 2880 epochs.
+pub const FAULT_MAX_AGE: u64 = 2880 * 14 - 1;
+
+/// An executed message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MsgLookup {
+    pub exit_code: i64,
+    /// height of the tipset the message is included in
+    pub height: u64,
+}
+
+/// Chain side of sealing: randomness and the pre-commit/commit messages.
+/// All methods are called on the state machine thread, they should not wait
+/// for the chain, e.g. for messages to land.
+pub trait SealingApi {
+    /// Height of the current chain head.
+    fn chain_head(&self) -> Result<u64>;
+    /// Get the seal ticket of the sector and the epoch it's drawn at.
+    fn ticket(&self, sector: &SectorInfo) -> Result<(SealTicket, u64)>;
+    /// Send the `PreCommitSector` message, return the message cid.
+    fn send_pre_commit(&self, sector: &SectorInfo) -> Result<Cid>;
+    /// Draw the interactive seed of the sector at `epoch`.
+    fn seed(&self, sector: &SectorInfo, epoch: u64) -> Result<SealSeed>;
+    /// Send the `ProveCommitSector` message, return the message cid.
+    fn send_commit(&self, sector: &SectorInfo) -> Result<Cid>;
+    /// Look up the message on chain, `None` while it's not executed.
+    fn search_msg(&self, msg: &Cid) -> Result<Option<MsgLookup>>;
+    /// Whether the sealed sector could still be proven, e.g. its sealed
+    /// file is present and readable.
+    fn check_provable(&self, sector: &SectorInfo) -> Result<bool>;
+    /// Send the `DeclareTemporaryFaults` message, return the message cid.
+    fn send_fault_declaration(&self, sector: &SectorInfo) -> Result<Cid>;
+    /// Send the fault recovery declaration, return the message cid.
+    fn send_recovery_declaration(&self, sector: &SectorInfo) -> Result<Cid>;
+    /// Send the `TerminateSectors` message, return the message cid.
+    fn send_termination(&self, sector: &SectorInfo) -> Result<Cid>;
+}
+
+/// Where the sealed, unsealed and cache files of sectors are kept.
+pub type SectorFiles = Arc<Mutex<dyn Store + Send>>;
+
+/// Create a sealer for a state machine, every sector is sealed with its own
+/// sealer so that sectors could be sealed concurrently.
+pub type SealerFactory = Box<dyn Fn() -> Box<dyn Sealer + Send> + Send + Sync>;
+
+/// Everything the state machines need to seal sectors of one miner, shared
+/// by the state machines of all sectors.
+pub struct SectorBuilder {
+    pub miner: u64,
+    pub seal_proof_type: RegisteredSealProof,
+    pub new_sealer: SealerFactory,
+    pub api: Box<dyn SealingApi + Send + Sync>,
+    /// where sector infos are saved, sectors are only in memory if `None`
+    pub store: Option<Arc<dyn SectorStore>>,
+    pub retry_policies: RetryPolicies,
+    /// how sectors learn that their seed epoch is on chain, sectors fail to
+    /// wait for the seed if `None`
+    pub seed_watcher: Option<Box<dyn SeedWatcher>>,
+    pub limiter: SealingLimiter,
+    /// where the files of removed sectors are dropped from, sectors fail to
+    /// be removed if `None`
+    pub sector_files: Option<SectorFiles>,
+    pub observers: TransitionObservers,
+    pub state_budgets: StateBudgets,
+}
+
+impl SectorBuilder {
+    pub fn new(
+        miner: u64,
+        seal_proof_type: RegisteredSealProof,
+        new_sealer: SealerFactory,
+        api: Box<dyn SealingApi + Send + Sync>,
+    ) -> Self {
+        SectorBuilder {
+            miner,
+            seal_proof_type,
+            new_sealer,
+            api,
+            store: None,
+            retry_policies: RetryPolicies::default(),
+            seed_watcher: None,
+            limiter: SealingLimiter::default(),
+            sector_files: None,
+            observers: TransitionObservers::default(),
+            state_budgets: StateBudgets::default(),
+        }
+    }
+
+    pub fn with_store(mut self, store: Arc<dyn SectorStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn with_retry_policies(mut self, retry_policies: RetryPolicies) -> Self {
+        self.retry_policies = retry_policies;
+        self
+    }
+
+    pub fn with_seed_watcher(mut self, seed_watcher: Box<dyn SeedWatcher>) -> Self {
+        self.seed_watcher = Some(seed_watcher);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: SealingLimits) -> Self {
+        self.limiter = SealingLimiter::new(limits);
+        self
+    }
+
+    pub fn with_sector_files(mut self, sector_files: SectorFiles) -> Self {
+        self.sector_files = Some(sector_files);
+        self
+    }
+
+    pub fn with_state_budgets(mut self, state_budgets: StateBudgets) -> Self {
+        self.state_budgets = state_budgets;
+        self
+    }
+
+    pub fn with_observer(self, observer: Arc<dyn TransitionObserver>) -> Self {
+        self.observers.add(observer);
+        self
+    }
+
+    pub fn sector_id(&self, sector: &SectorInfo) -> SectorId {
+        SectorId {
+            miner: self.miner,
+            number: sector.sector_id,
+        }
+    }
+}
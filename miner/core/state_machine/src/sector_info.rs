@@ -1,34 +1,62 @@
 // Copyright 2020 PolkaX
 
+use std::collections::BTreeMap;
+
 use cid::{Cid, Codec, IntoExt};
 use filecoin_proofs_api::{ChallengeSeed, Commitment, PieceInfo, Ticket};
+use serde::{Deserialize, Serialize};
 
-use crate::SectorState;
+use crate::{EventError, SectorState};
 
 pub type Piece = PieceInfo;
-type SealTicket = Ticket;
-type SealSeed = ChallengeSeed;
+pub type SealTicket = Ticket;
+pub type SealSeed = ChallengeSeed;
 
-fn zero_cid() -> Cid {
+pub(crate) fn zero_cid() -> Cid {
     Cid::new_v1(Codec::Raw, multihash::Identity::digest(b"").into_ext())
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SectorInfo {
     pub state: SectorState,
-    sector_id: u64,
-    nonce: u64,
-
-    pieces: Vec<Piece>,
-    commd: Commitment,
-    commr: Commitment,
-    proof: Commitment,
-    ticket: SealTicket,
-
-    pre_commit_msg: Cid,
-    seed: SealSeed,
-    commit_msg: Cid,
-    fault_report_msg: Cid,
+    pub sector_id: u64,
+    pub nonce: u64,
+
+    pub pieces: Vec<Piece>,
+    pub commd: Commitment,
+    pub commr: Commitment,
+    pub proof: Vec<u8>,
+    pub ticket: SealTicket,
+    pub ticket_epoch: u64,
+
+    pub pre_commit_msg: Cid,
+    pub seed: SealSeed,
+    pub seed_epoch: u64,
+    pub commit_msg: Cid,
+    pub fault_report_msg: Cid,
+    /// chain epoch the current fault is detected at
+    #[serde(default)]
+    pub fault_epoch: u64,
+    #[serde(default = "zero_cid")]
+    pub recovery_msg: Cid,
+    #[serde(default = "zero_cid")]
+    pub termination_msg: Cid,
+
+    /// seconds since the unix epoch the sector entered its state, 0 for
+    /// sectors saved without it
+    #[serde(default)]
+    pub state_entered: u64,
+    /// the sector stayed in its state longer than the `StateBudgets`
+    #[serde(default)]
+    pub stuck: bool,
+
+    /// retries made from each failure state
+    #[serde(default)]
+    pub retries: BTreeMap<SectorState, u32>,
+    /// transition waiting for room in its state under the `SealingLimits`,
+    /// with the error of the failed work for a transition to a failure state
+    #[serde(default)]
+    pub queued: Option<(SectorState, Option<String>)>,
 }
 
 impl SectorInfo {
@@ -40,12 +68,62 @@ impl SectorInfo {
             pieces: vec![],
             commd: [0; 32],
             commr: [0; 32],
-            proof: [0; 32],
+            proof: vec![],
             ticket: [0; 32],
+            ticket_epoch: 0,
             pre_commit_msg: zero_cid(),
             seed: [0; 32],
+            seed_epoch: 0,
             commit_msg: zero_cid(),
             fault_report_msg: zero_cid(),
+            fault_epoch: 0,
+            recovery_msg: zero_cid(),
+            termination_msg: zero_cid(),
+            state_entered: 0,
+            stuck: false,
+            retries: BTreeMap::new(),
+            queued: None,
+        }
+    }
+
+    /// Check that the sector has everything the work of `state` and later
+    /// states needs, before forcing the sector into `state`.
+    pub fn check_state(&self, state: &SectorState) -> Result<(), EventError> {
+        use SectorState::*;
+
+        // how far sealing must have got for `state`
+        let stage = match state {
+            UndefinedSectorState | Empty | PackingFailed | FailedUnrecoverable | Removing
+            | RemoveFailed | Removed => 0,
+            Packing | Unsealed | SealFailed => 1,
+            PreCommitting | PreCommitFailed => 2,
+            WaitSeed => 3,
+            Committing | SealCommitFailed | CommitFailed => 4,
+            CommitWait => 5,
+            FinalizeSector | Proving | Faulty | FaultReportFailed | FaultReported
+            | FaultedFinal | Recovering | RecoveryWait | RecoveryFailed | Terminating
+            | TerminateWait | TerminateFailed => 6,
+        };
+        let zero = zero_cid();
+        let missing = if stage >= 1 && self.pieces.is_empty() {
+            Some("pieces")
+        } else if stage >= 2 && (self.commr == [0; 32] || self.commd == [0; 32]) {
+            Some("commr and commd")
+        } else if stage >= 3 && self.pre_commit_msg == zero {
+            Some("pre_commit_msg")
+        } else if stage >= 4 && self.seed == [0; 32] {
+            Some("seed")
+        } else if stage >= 5 && (self.proof.is_empty() || self.commit_msg == zero) {
+            Some("proof and commit_msg")
+        } else {
+            None
+        };
+        match missing {
+            Some(field) => Err(EventError::MissingField {
+                state: state.clone(),
+                field,
+            }),
+            None => Ok(()),
         }
     }
 }
@@ -0,0 +1,98 @@
+// Copyright 2020 PolkaX
+
+use anyhow::Result;
+use crossbeam::channel::{Receiver, Sender};
+use events::{Events, TriggerInfo};
+use log::warn;
+
+use crate::sealing::INTERACTIVE_POREP_CONFIDENCE;
+use crate::{Event, EventType, SeedEpoch, TARGET};
+
+/// A state machine waiting for the seed epoch of its sector.
+#[derive(Clone, Debug)]
+pub struct SeedRequest {
+    pub sector_id: u64,
+    pub seed_epoch: u64,
+    /// where `SeedReady` and `SeedReverted` of the sector are sent
+    pub notify: Sender<Event>,
+}
+
+/// Watch the chain for the seed epochs of sectors.
+pub trait SeedWatcher: Send + Sync {
+    fn watch(&self, request: SeedRequest) -> Result<()>;
+}
+
+/// `Events` can't be shared with the state machine threads, requests are
+/// sent to the thread owning `Events`, which registers them by
+/// `register_seed_requests`.
+impl SeedWatcher for Sender<SeedRequest> {
+    fn watch(&self, request: SeedRequest) -> Result<()> {
+        Ok(self.send(request)?)
+    }
+}
+
+/// Register a `chain_at` trigger for every received request and every
+/// request in `pending`, return the number of registered requests. The
+/// sector is sent `SeedReady` once its seed epoch got
+/// `INTERACTIVE_POREP_CONFIDENCE`, and `SeedReverted` if the seed epoch is
+/// reverted after that. Requests failed to be registered, e.g. before the
+/// first head change, are left in `pending` for the next call.
+pub fn register_seed_requests(
+    events: &mut Events,
+    requests: &Receiver<SeedRequest>,
+    pending: &mut Vec<SeedRequest>,
+) -> usize {
+    pending.extend(requests.try_iter());
+    let mut registered = 0;
+    for request in std::mem::take(pending) {
+        match register_seed_request(events, &request) {
+            Ok(()) => registered += 1,
+            Err(e) => {
+                warn!(
+                    target: TARGET,
+                    "registering seed of sector {} failed: {:?}", request.sector_id, e
+                );
+                pending.push(request);
+            }
+        }
+    }
+    registered
+}
+
+fn register_seed_request(events: &mut Events, request: &SeedRequest) -> Result<()> {
+    let (sector_id, seed_epoch) = (request.sector_id, request.seed_epoch);
+    let notify = request.notify.clone();
+    let revert_notify = request.notify.clone();
+    let id = events.chain_at(
+        Box::new(move |_, _| {
+            let ready = EventType::SeedReady(SeedEpoch {
+                id: sector_id,
+                epoch: seed_epoch,
+            });
+            if notify.send(Event::new(ready)).is_err() {
+                warn!(target: TARGET, "seed of stopped sector {} is ready", sector_id);
+            }
+            Ok(())
+        }),
+        Box::new(move |_| {
+            let reverted = EventType::SeedReverted(SeedEpoch {
+                id: sector_id,
+                epoch: seed_epoch,
+            });
+            if revert_notify.send(Event::new(reverted)).is_err() {
+                warn!(target: TARGET, "seed of stopped sector {} is reverted", sector_id);
+            }
+            Ok(())
+        }),
+        INTERACTIVE_POREP_CONFIDENCE,
+        seed_epoch,
+    )?;
+    events.describe_trigger(
+        id,
+        TriggerInfo {
+            sector_id: Some(sector_id),
+            purpose: "seed".to_string(),
+        },
+    );
+    Ok(())
+}
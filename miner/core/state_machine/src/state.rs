@@ -1,6 +1,12 @@
 // Copyright 2020 PolkaX
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::EventError;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum SectorState {
     UndefinedSectorState,
     Empty,
@@ -19,6 +25,76 @@ pub enum SectorState {
     PackingFailed,
     FailedUnrecoverable,
     Faulty,
+    FaultReportFailed,
     FaultReported,
     FaultedFinal,
+    Recovering,
+    RecoveryWait,
+    RecoveryFailed,
+    Terminating,
+    TerminateWait,
+    TerminateFailed,
+    Removing,
+    RemoveFailed,
+    Removed,
+}
+
+impl SectorState {
+    /// The state machine has nothing more to do in this state unless an
+    /// external event arrives, such sectors are not resumed after restart.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            SectorState::Proving
+                | SectorState::FailedUnrecoverable
+                | SectorState::FaultedFinal
+                | SectorState::Removed
+        )
+    }
+
+    /// The sector failed and is retried according to its `RetryPolicy`.
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            SectorState::PackingFailed
+                | SectorState::SealFailed
+                | SectorState::PreCommitFailed
+                | SectorState::SealCommitFailed
+                | SectorState::CommitFailed
+                | SectorState::FaultReportFailed
+                | SectorState::RecoveryFailed
+                | SectorState::TerminateFailed
+                | SectorState::RemoveFailed
+        )
+    }
+
+    /// The failure state the sector falls into when the work of this state
+    /// fails, `None` for states without work.
+    pub fn failure_state(&self) -> Option<SectorState> {
+        let failed = match self {
+            SectorState::Packing => SectorState::PackingFailed,
+            SectorState::Unsealed => SectorState::SealFailed,
+            SectorState::PreCommitting | SectorState::WaitSeed => SectorState::PreCommitFailed,
+            SectorState::Committing => SectorState::SealCommitFailed,
+            SectorState::CommitWait => SectorState::CommitFailed,
+            SectorState::FinalizeSector => SectorState::FailedUnrecoverable,
+            SectorState::Faulty => SectorState::FaultReportFailed,
+            SectorState::Recovering | SectorState::RecoveryWait => SectorState::RecoveryFailed,
+            SectorState::Terminating | SectorState::TerminateWait => SectorState::TerminateFailed,
+            SectorState::Removing => SectorState::RemoveFailed,
+            _ => return None,
+        };
+        Some(failed)
+    }
+}
+
+impl FromStr for SectorState {
+    type Err = EventError;
+
+    /// Parse the variant name, the same one `Debug` prints and the saved
+    /// infos use.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| EventError::UnknownState(s.to_string()))
+    }
 }
@@ -1,89 +1,707 @@
 // Copyright 2020 PolkaX
 
-use crate::event::SectorStart;
-use crate::{Event, EventRet, Handler, SectorBuilder, SectorInfo, SectorState};
-use crossbeam::deque::{Steal, Stealer};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
+use anyhow::{bail, Result};
+use cid::Cid;
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+use filecoin_proofs_api::seal::SealPreCommitPhase2Output;
+use log::{error, info, warn};
+use specs_storage::Sealer;
+use stores::error::StoresError;
+use stores::filetype::SectorFileTypes;
+
+use crate::event::{SectorFault, SectorStart, SeedEpoch, StateUpdate, StuckSector};
+use crate::history::unix_time;
+use crate::sealing::{EXIT_CODE_OK, MAX_TICKET_AGE, PRE_COMMIT_CHALLENGE_DELAY};
+use crate::sector_info::zero_cid;
+use crate::transition::is_allowed;
+use crate::{
+    Event, EventError, EventRet, EventType, Handler, MsgLookup, SectorBuilder, SectorInfo,
+    SectorLogEntry, SectorState, SeedRequest, TARGET,
+};
+
+/// How often a queued transition checks for room in its state.
+const QUEUE_RECHECK: Duration = Duration::from_millis(500);
+
+/// How often a sector waiting for a message looks it up again.
+const MSG_RECHECK: Duration = Duration::from_secs(30);
+
+/// State machine of one sector.
 pub struct StateMachine {
     state: SectorInfo,
-    sb: SectorBuilder,
-    stealer: Stealer<Event>,
+    // copy of `state` which could be read by other threads
+    shared: Arc<RwLock<SectorInfo>>,
+    sb: Arc<SectorBuilder>,
+    sealer: Box<dyn Sealer + Send>,
+    events: Receiver<Event>,
+    // sender of `events`, for chain triggers of the sector
+    sender: Sender<Event>,
+    // when the sector in a failure state is retried
+    retry_at: Option<Instant>,
+    // the seed epoch is watched since the state machine started
+    seed_watched: bool,
+    // when the message the sector is waiting for is looked up again
+    msg_recheck_at: Option<Instant>,
+    // when the sector entered its current state, or the state machine
+    // started for a resumed sector
+    entered_at: Instant,
 }
 
 impl StateMachine {
-    pub fn new(stealer: Stealer<Event>) -> Self {
+    pub fn new(
+        sender: Sender<Event>,
+        events: Receiver<Event>,
+        sb: Arc<SectorBuilder>,
+        shared: Arc<RwLock<SectorInfo>>,
+    ) -> Self {
+        let mut state = shared.read().unwrap().clone();
+        if state.state != SectorState::Empty {
+            sb.limiter.update(state.sector_id, &state.state);
+        }
+        // sectors saved before the entry time was recorded are counted in
+        // their state from now on
+        if state.state_entered == 0 {
+            state.state_entered = unix_time(SystemTime::now());
+            *shared.write().unwrap() = state.clone();
+        }
         StateMachine {
-            state: SectorInfo::new(),
-            sb: SectorBuilder {},
-            stealer,
+            state,
+            shared,
+            sealer: (sb.new_sealer)(),
+            sb,
+            events,
+            sender,
+            retry_at: None,
+            seed_watched: false,
+            msg_recheck_at: None,
+            entered_at: Instant::now(),
         }
     }
 
+    /// Handle events and do the work of the sector until an `Exit` event.
+    /// Blocks on the event channel while there is no work to do.
     pub fn run(&mut self) {
         loop {
-            if let Steal::Success(event) = self.stealer.steal() {
-                let ret = event.handle(self);
-                if ret == Ok(EventRet::Exit) {
-                    // To do: 退出清理操作
-                    println!("recv Exit signal");
+            let event = match self.next_event() {
+                Ok(event) => event,
+                Err(()) => break,
+            };
+            if let Some(event) = event {
+                if self.apply(event) == Ok(EventRet::Exit) {
                     break;
                 }
             }
             self.state_transition();
+            self.publish();
         }
+        self.shutdown();
     }
 
-    pub fn handle_packing(&self, sector_start: &SectorStart) {}
-
-    /////
-    // Now decide what to do next
-
-    /*
-
-        *   Empty
-        |   |
-        |   v
-        *<- Packing <- incoming
-        |   |
-        |   v
-        *<- Unsealed <--> SealFailed
-        |   |
-        |   v
-        *   PreCommitting <--> PreCommitFailed
-        |   |                  ^
-        |   v                  |
-        *<- WaitSeed ----------/
-        |   |||
-        |   vvv      v--> SealCommitFailed
-        *<- Committing
-        |   |        ^--> CommitFailed
-        |   v             ^
-        *<- CommitWait ---/
-        |   |
-        |   v
-        *<- Proving
-        |
-        v
-        FailedUnrecoverable
-
-        UndefinedSectorState <- ¯\_(ツ)_/¯
-            |                     ^
-            *---------------------/
-
-    */
-    fn state_transition(&mut self) {
+    /// Take the next event without waiting when there is work to do, or
+    /// wait for it until the next retry. `Err` if the channel is closed.
+    fn next_event(&self) -> Result<Option<Event>, ()> {
+        let timeout = match self.wait_time() {
+            Some(timeout) => timeout,
+            None => return self.events.recv().map(Some).map_err(|_| ()),
+        };
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => Ok(Some(event)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(()),
+        }
+    }
+
+    /// How long the state machine could wait for events, `None` while the
+    /// sector only changes by events.
+    fn wait_time(&self) -> Option<Duration> {
+        if self.state.queued.is_some() {
+            return Some(QUEUE_RECHECK);
+        }
         match self.state.state {
-            SectorState::Empty => {}
-            SectorState::Packing => {}
-            SectorState::Unsealed => {}
-            SectorState::PreCommitting => {}
-            SectorState::WaitSeed => {}
-            SectorState::Committing => {}
-            SectorState::CommitWait => {}
-            SectorState::Proving => {}
-            SectorState::FailedUnrecoverable => {}
-            SectorState::UndefinedSectorState => {}
-            _ => {}
+            SectorState::Packing
+            | SectorState::Unsealed
+            | SectorState::PreCommitting
+            | SectorState::Committing
+            | SectorState::FinalizeSector
+            | SectorState::Recovering
+            | SectorState::Terminating
+            | SectorState::Removing => Some(Duration::from_secs(0)),
+            // waiting for a message
+            SectorState::WaitSeed if self.seed_watched => None,
+            SectorState::WaitSeed
+            | SectorState::CommitWait
+            | SectorState::Faulty
+            | SectorState::RecoveryWait
+            | SectorState::TerminateWait => Some(
+                self.msg_recheck_at
+                    .map(|at| at.saturating_duration_since(Instant::now()))
+                    .unwrap_or_default(),
+            ),
+            ref state if state.is_failure() => Some(
+                self.retry_at
+                    .map(|at| at.saturating_duration_since(Instant::now()))
+                    .unwrap_or_default(),
+            ),
+            _ => None,
+        }
+    }
+
+    fn apply(&mut self, event: Event) -> Result<EventRet, EventError> {
+        let before = self.state.state.clone();
+        let ret = event.handle(self);
+        if event.sector_id().is_some() {
+            let error = ret.as_ref().err().map(|e| e.to_string());
+            // a forced update counts even into the same state
+            let updated = matches!(
+                (event.event_type(), &ret),
+                (EventType::UpdateState(_), Ok(_))
+            );
+            if before != self.state.state || updated {
+                self.observe(&before, Some(event.event_type().name()));
+            }
+            self.record(Some(&event), before, error);
+        }
+        ret
+    }
+
+    /// Make the current state visible to other threads, and save it.
+    fn publish(&self) {
+        let mut shared = self.shared.write().unwrap();
+        if *shared != self.state {
+            // the limiter keeps the queued transition until it's entered
+            if self.state.queued.is_none() {
+                self.sb
+                    .limiter
+                    .update(self.state.sector_id, &self.state.state);
+            }
+            *shared = self.state.clone();
+            drop(shared);
+            self.persist();
+        }
+    }
+
+    /// Apply the events already sent, and save the state, without starting
+    /// any new work.
+    fn shutdown(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            // errors are in the event log, and a repeated `Exit` is no-op
+            let _ = self.apply(event);
+        }
+        self.publish();
+        info!(
+            target: TARGET,
+            "state machine of sector {} stopped in {:?}", self.state.sector_id, self.state.state
+        );
+    }
+
+    /// Save the sector, or delete it once it's removed.
+    fn persist(&self) {
+        if let Some(store) = self.sb.store.as_ref() {
+            let result = if self.state.state == SectorState::Removed {
+                store.remove(self.state.sector_id)
+            } else {
+                store.save(&self.state)
+            };
+            if let Err(e) = result {
+                error!(
+                    target: TARGET,
+                    "saving sector {} failed: {:?}", self.state.sector_id, e
+                );
+            }
+        }
+    }
+
+    /// Append the transition from `from` to the current state to the event
+    /// log of the sector.
+    fn record(&self, event: Option<&Event>, from: SectorState, error: Option<String>) {
+        if let Some(store) = self.sb.store.as_ref() {
+            let entry = SectorLogEntry::new(
+                event.map(|e| e.event_type().name().to_string()),
+                from,
+                self.state.state.clone(),
+                error,
+            )
+            .with_reason(event.and_then(Event::reason).map(str::to_string));
+            if let Err(e) = store.append_log(self.state.sector_id, &entry) {
+                error!(
+                    target: TARGET,
+                    "saving log of sector {} failed: {:?}", self.state.sector_id, e
+                );
+            }
+        }
+    }
+
+    /// Check that `event` could move the sector to `to`.
+    fn check_event(&self, event: &'static str, to: SectorState) -> Result<(), EventError> {
+        if is_allowed(&self.state.state, &to) {
+            return Ok(());
+        }
+        error!(
+            target: TARGET,
+            "sector {} is {:?}, could not handle {}", self.state.sector_id, self.state.state, event
+        );
+        Err(EventError::IllegalEvent {
+            event,
+            state: self.state.state.clone(),
+        })
+    }
+
+    pub fn handle_packing(&mut self, sector_start: &SectorStart) -> Result<(), EventError> {
+        self.check_event("Packing", SectorState::Packing)?;
+        self.state.sector_id = sector_start.id;
+        self.state.pieces = sector_start.pieces.clone();
+        self.enter(SectorState::Packing);
+        Ok(())
+    }
+
+    pub fn handle_fault_detected(&mut self, fault: &SectorFault) -> Result<(), EventError> {
+        self.check_event("FaultDetected", SectorState::Faulty)?;
+        self.state.fault_epoch = fault.epoch;
+        // declared again for the new fault
+        self.state.fault_report_msg = zero_cid();
+        self.state.state = SectorState::Faulty;
+        Ok(())
+    }
+
+    /// Declare the recovery on chain, the sector goes back to proving once
+    /// the declaration landed.
+    pub fn handle_fault_recovered(&mut self) -> Result<(), EventError> {
+        self.check_event("FaultRecovered", SectorState::Recovering)?;
+        self.state.state = SectorState::Recovering;
+        Ok(())
+    }
+
+    pub fn handle_fault_expired(&mut self) -> Result<(), EventError> {
+        self.check_event("FaultExpired", SectorState::FaultedFinal)?;
+        self.state.state = SectorState::FaultedFinal;
+        Ok(())
+    }
+
+    /// Draw the seed once its epoch got enough confidence.
+    pub fn handle_seed_ready(&mut self, seed: &SeedEpoch) -> Result<(), EventError> {
+        self.check_event("SeedReady", SectorState::Committing)?;
+        self.check_seed_epoch(seed)?;
+        self.state.seed = self
+            .sb
+            .api
+            .seed(&self.state, seed.epoch)
+            .map_err(|e| EventError::Api(format!("{:#}", e)))?;
+        self.enter(SectorState::Committing);
+        Ok(())
+    }
+
+    /// Go back to waiting, the seed is drawn again when its epoch is on
+    /// chain again.
+    pub fn handle_seed_reverted(&mut self, seed: &SeedEpoch) -> Result<(), EventError> {
+        self.check_seed_epoch(seed)?;
+        if self.state.state == SectorState::WaitSeed {
+            // the drawn seed may be waiting for room in `Committing`
+            self.state.queued = None;
+            self.state.seed = [0; 32];
+            return Ok(());
+        }
+        self.check_event("SeedReverted", SectorState::WaitSeed)?;
+        self.state.seed = [0; 32];
+        self.state.state = SectorState::WaitSeed;
+        self.retry_at = None;
+        Ok(())
+    }
+
+    fn check_seed_epoch(&self, seed: &SeedEpoch) -> Result<(), EventError> {
+        if seed.epoch != self.state.seed_epoch {
+            // from a trigger of a previous pre-commit
+            return Err(EventError::Api(format!(
+                "seed epoch {} is not {}",
+                seed.epoch, self.state.seed_epoch
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn handle_terminate(&mut self) -> Result<(), EventError> {
+        self.check_event("Terminate", SectorState::Terminating)?;
+        self.state.state = SectorState::Terminating;
+        Ok(())
+    }
+
+    pub fn handle_remove(&mut self) -> Result<(), EventError> {
+        self.check_event("Remove", SectorState::Removing)?;
+        self.state.state = SectorState::Removing;
+        self.retry_at = None;
+        Ok(())
+    }
+
+    /// Mark the sector stuck, and fail the work of its state for a retry if
+    /// `StateBudgets::retry` is set.
+    pub fn handle_stuck(&mut self, stuck: &StuckSector) -> Result<(), EventError> {
+        if stuck.state != self.state.state {
+            // moved on since it's found stuck
+            return Ok(());
+        }
+        warn!(
+            target: TARGET,
+            "sector {} is stuck in {:?} for {:?}", stuck.id, stuck.state, stuck.elapsed
+        );
+        self.state.stuck = true;
+        if !self.sb.state_budgets.retry {
+            return Ok(());
+        }
+        let failed = match self.state.state.failure_state() {
+            Some(failed) => failed,
+            None => return Ok(()),
+        };
+        self.check_event("Stuck", failed.clone())?;
+        self.state.state = failed;
+        self.state.queued = None;
+        Ok(())
+    }
+
+    /// Force the sector into a state, bypassing `TRANSITIONS`.
+    pub fn handle_update_state(&mut self, update: &StateUpdate) -> Result<(), EventError> {
+        self.state.check_state(&update.state)?;
+        info!(
+            target: TARGET,
+            "sector {}: update state {:?} -> {:?}: {}",
+            self.state.sector_id,
+            self.state.state,
+            update.state,
+            update.reason
+        );
+        self.state.state = update.state.clone();
+        self.retry_at = None;
+        self.msg_recheck_at = None;
+        self.state.queued = None;
+        Ok(())
+    }
+
+    /// Do the work of the current state, and move to the next state. The
+    /// allowed transitions are listed in `TRANSITIONS`.
+    fn state_transition(&mut self) {
+        if let Some((next, error)) = self.state.queued.take() {
+            self.transit(next, error);
+            return;
+        }
+        if self.state.state.is_failure() {
+            self.handle_failure();
+            return;
+        }
+        let failed = match self.state.state.failure_state() {
+            Some(failed) => failed,
+            // nothing to do, or waiting for external events
+            None => return,
+        };
+        let result = match self.state.state {
+            SectorState::Packing => self.handle_pack(),
+            SectorState::Unsealed => self.handle_unsealed(),
+            SectorState::PreCommitting => self.handle_pre_committing(),
+            SectorState::WaitSeed => self.handle_wait_seed(),
+            SectorState::Committing => self.handle_committing(),
+            SectorState::CommitWait => self.handle_commit_wait(),
+            SectorState::FinalizeSector => self.handle_finalize(),
+            SectorState::Faulty => self.handle_faulty(),
+            SectorState::Recovering => self.handle_recovering(),
+            SectorState::RecoveryWait => self.handle_recovery_wait(),
+            SectorState::Terminating => self.handle_terminating(),
+            SectorState::TerminateWait => self.handle_terminate_wait(),
+            SectorState::Removing => self.handle_removing(),
+            _ => return,
+        };
+        let (next, error) = match result {
+            // still waiting for events
+            Ok(next) if next == self.state.state => return,
+            Ok(next) => (next, None),
+            Err(e) => {
+                error!(
+                    target: TARGET,
+                    "sector {} failed in {:?}: {:?}", self.state.sector_id, self.state.state, e
+                );
+                (failed, Some(format!("{:#}", e)))
+            }
+        };
+        self.transit(next, error);
+    }
+
+    /// Move to `next` if the transition is allowed, or fall into
+    /// `UndefinedSectorState`.
+    fn transit(&mut self, next: SectorState, error: Option<String>) {
+        let (next, error) = if is_allowed(&self.state.state, &next) {
+            (next, error)
+        } else {
+            let error = format!("illegal transition {:?} -> {:?}", self.state.state, next);
+            error!(
+                target: TARGET,
+                "sector {}: {}", self.state.sector_id, error
+            );
+            (SectorState::UndefinedSectorState, Some(error))
+        };
+        if !self.sb.limiter.try_enter(self.state.sector_id, &next) {
+            info!(
+                target: TARGET,
+                "sector {}: {:?} -> {:?} is queued", self.state.sector_id, self.state.state, next
+            );
+            self.state.queued = Some((next, error));
+            return;
+        }
+        info!(
+            target: TARGET,
+            "sector {}: {:?} -> {:?}", self.state.sector_id, self.state.state, next
+        );
+        let from = std::mem::replace(&mut self.state.state, next);
+        self.observe(&from, None);
+        self.record(None, from, error);
+    }
+
+    /// Notify the observers of the transition from `from` to the current
+    /// state, which is entered now.
+    fn observe(&mut self, from: &SectorState, event: Option<&str>) {
+        let now = Instant::now();
+        let duration = now.duration_since(std::mem::replace(&mut self.entered_at, now));
+        self.state.state_entered = unix_time(SystemTime::now());
+        self.state.stuck = false;
+        self.msg_recheck_at = None;
+        self.sb.observers.notify(
+            self.state.sector_id,
+            from,
+            &self.state.state,
+            event,
+            duration,
+        );
+    }
+
+    /// Enter `to` on an event, or queue the transition if there is no room
+    /// in `to`.
+    fn enter(&mut self, to: SectorState) {
+        if self.sb.limiter.try_enter(self.state.sector_id, &to) {
+            self.state.state = to;
+        } else {
+            self.state.queued = Some((to, None));
+        }
+    }
+
+    /// Roll back to the failed step after the backoff of the retry policy,
+    /// or give up when all attempts are used.
+    fn handle_failure(&mut self) {
+        let failed = self.state.state.clone();
+        let policy = self.sb.retry_policies.get(&failed);
+        let attempts = self.state.retries.get(&failed).copied().unwrap_or(0);
+        let (next, error) = if attempts >= policy.max_attempts {
+            let error = format!("gave up in {:?} after {} retries", failed, attempts);
+            (SectorState::FailedUnrecoverable, Some(error))
+        } else {
+            let retry_at = *self
+                .retry_at
+                .get_or_insert_with(|| Instant::now() + policy.backoff(attempts));
+            if Instant::now() < retry_at {
+                return;
+            }
+            self.retry_at = None;
+            self.state.retries.insert(failed, attempts + 1);
+            (policy.retry_state, None)
+        };
+        self.transit(next, error);
+    }
+
+    /// Look up `msg` the sector is waiting for, once it's time to recheck
+    /// it. `None` while it's not executed, fails if it's executed with an
+    /// error.
+    fn search_msg(&mut self, msg: Cid, name: &str) -> Result<Option<MsgLookup>> {
+        if let Some(at) = self.msg_recheck_at {
+            if Instant::now() < at {
+                return Ok(None);
+            }
+        }
+        let lookup = match self.sb.api.search_msg(&msg)? {
+            Some(lookup) => lookup,
+            None => {
+                self.msg_recheck_at = Some(Instant::now() + MSG_RECHECK);
+                return Ok(None);
+            }
+        };
+        self.msg_recheck_at = None;
+        if lookup.exit_code != EXIT_CODE_OK {
+            bail!("{} failed with exit code {}", name, lookup.exit_code);
+        }
+        Ok(Some(lookup))
+    }
+
+    fn handle_pack(&mut self) -> Result<SectorState> {
+        if self.state.pieces.is_empty() {
+            bail!("no pieces to pack");
+        }
+        Ok(SectorState::Unsealed)
+    }
+
+    /// PC1 and PC2
+    fn handle_unsealed(&mut self) -> Result<SectorState> {
+        let sector = self.sb.sector_id(&self.state);
+        let (ticket, ticket_epoch) = self.sb.api.ticket(&self.state)?;
+
+        let pc1o = self
+            .sealer
+            .seal_pre_commit1(sector, ticket, &self.state.pieces)?;
+        let pc2o = self.sealer.seal_pre_commit2(sector, pc1o)?;
+
+        self.state.ticket = ticket;
+        self.state.ticket_epoch = ticket_epoch;
+        self.state.commd = pc2o.comm_d;
+        self.state.commr = pc2o.comm_r;
+        Ok(SectorState::PreCommitting)
+    }
+
+    /// Send the pre-commit message, or redo PC1 with a new ticket if the
+    /// ticket is too old to be accepted.
+    fn handle_pre_committing(&mut self) -> Result<SectorState> {
+        let head = self.sb.api.chain_head()?;
+        if head > self.state.ticket_epoch + MAX_TICKET_AGE {
+            info!(
+                target: TARGET,
+                "sector {}: ticket of epoch {} expired at {}",
+                self.state.sector_id,
+                self.state.ticket_epoch,
+                head
+            );
+            return Ok(SectorState::Unsealed);
+        }
+        self.state.pre_commit_msg = self.sb.api.send_pre_commit(&self.state)?;
+        // the seed epoch of the new pre-commit is watched again
+        self.seed_watched = false;
+        Ok(SectorState::WaitSeed)
+    }
+
+    /// Wait for the pre-commit to land, and watch the seed epoch, the sector
+    /// stays here until `SeedReady`.
+    fn handle_wait_seed(&mut self) -> Result<SectorState> {
+        if self.seed_watched {
+            return Ok(SectorState::WaitSeed);
+        }
+        let msg = self.state.pre_commit_msg.clone();
+        let lookup = match self.search_msg(msg, "pre-commit message")? {
+            Some(lookup) => lookup,
+            None => return Ok(SectorState::WaitSeed),
+        };
+        let seed_watcher = match self.sb.seed_watcher.as_ref() {
+            Some(seed_watcher) => seed_watcher,
+            None => bail!("no seed watcher"),
+        };
+        self.state.seed_epoch = lookup.height + PRE_COMMIT_CHALLENGE_DELAY;
+        seed_watcher.watch(SeedRequest {
+            sector_id: self.state.sector_id,
+            seed_epoch: self.state.seed_epoch,
+            notify: self.sender.clone(),
+        })?;
+        self.seed_watched = true;
+        Ok(SectorState::WaitSeed)
+    }
+
+    /// C1, C2 and sending the commit message
+    fn handle_committing(&mut self) -> Result<SectorState> {
+        let sector = self.sb.sector_id(&self.state);
+        let pc2o = SealPreCommitPhase2Output {
+            registered_proof: self.sb.seal_proof_type,
+            comm_r: self.state.commr,
+            comm_d: self.state.commd,
+        };
+        let c1o = self.sealer.seal_commit1(
+            sector,
+            self.state.ticket,
+            self.state.seed,
+            &self.state.pieces,
+            pc2o,
+        )?;
+        let proof = self.sealer.seal_commit2(sector, c1o)?;
+        self.state.proof = proof.proof;
+
+        // failing to send the message is not a sealing failure
+        match self.sb.api.send_commit(&self.state) {
+            Ok(msg) => {
+                self.state.commit_msg = msg;
+                Ok(SectorState::CommitWait)
+            }
+            Err(e) => {
+                error!(
+                    target: TARGET,
+                    "sector {}: sending commit message failed: {:?}", self.state.sector_id, e
+                );
+                Ok(SectorState::CommitFailed)
+            }
+        }
+    }
+
+    fn handle_commit_wait(&mut self) -> Result<SectorState> {
+        let msg = self.state.commit_msg.clone();
+        match self.search_msg(msg, "commit message")? {
+            Some(_) => Ok(SectorState::FinalizeSector),
+            None => Ok(SectorState::CommitWait),
+        }
+    }
+
+    fn handle_finalize(&mut self) -> Result<SectorState> {
+        let sector = self.sb.sector_id(&self.state);
+        self.sealer.finalize_sector(sector)?;
+        Ok(SectorState::Proving)
+    }
+
+    /// Declare the fault on chain, and wait for the declaration to land.
+    fn handle_faulty(&mut self) -> Result<SectorState> {
+        if self.state.fault_report_msg == zero_cid() {
+            self.state.fault_report_msg = self.sb.api.send_fault_declaration(&self.state)?;
+        }
+        let msg = self.state.fault_report_msg.clone();
+        match self.search_msg(msg, "fault declaration") {
+            Ok(Some(_)) => Ok(SectorState::FaultReported),
+            Ok(None) => Ok(SectorState::Faulty),
+            Err(e) => {
+                // declared again on retry
+                self.state.fault_report_msg = zero_cid();
+                Err(e)
+            }
+        }
+    }
+
+    fn handle_recovering(&mut self) -> Result<SectorState> {
+        self.state.recovery_msg = self.sb.api.send_recovery_declaration(&self.state)?;
+        Ok(SectorState::RecoveryWait)
+    }
+
+    fn handle_recovery_wait(&mut self) -> Result<SectorState> {
+        let msg = self.state.recovery_msg.clone();
+        match self.search_msg(msg, "recovery declaration")? {
+            Some(_) => Ok(SectorState::Proving),
+            None => Ok(SectorState::RecoveryWait),
+        }
+    }
+
+    fn handle_terminating(&mut self) -> Result<SectorState> {
+        self.state.termination_msg = self.sb.api.send_termination(&self.state)?;
+        Ok(SectorState::TerminateWait)
+    }
+
+    fn handle_terminate_wait(&mut self) -> Result<SectorState> {
+        let msg = self.state.termination_msg.clone();
+        match self.search_msg(msg, "termination message")? {
+            Some(_) => Ok(SectorState::Removing),
+            None => Ok(SectorState::TerminateWait),
+        }
+    }
+
+    /// Drop all files of the sector, its saved info is deleted once it's
+    /// `Removed`.
+    fn handle_removing(&mut self) -> Result<SectorState> {
+        let sector_files = match self.sb.sector_files.as_ref() {
+            Some(sector_files) => sector_files,
+            None => bail!("no sector files"),
+        };
+        let sector = self.sb.sector_id(&self.state);
+        let mut sector_files = sector_files.lock().unwrap();
+        for file_type in SectorFileTypes::iter() {
+            match sector_files.remove(sector, *file_type) {
+                // the sector failed before the file is created
+                Ok(()) | Err(StoresError::NotFoundSector(..)) => {}
+                Err(e) => bail!("removing {:?} files: {}", file_type, e),
+            }
         }
+        Ok(SectorState::Removed)
     }
 }
@@ -0,0 +1,218 @@
+// Copyright 2020 PolkaX
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use datastore::{key::Key, Batching};
+
+use crate::history::unix_time;
+use crate::{is_allowed, EventError, SectorInfo, SectorLogEntry, SectorState, TransitionObservers};
+
+/// Persistent storage of sector infos, written on every state transition.
+pub trait SectorStore: Send + Sync {
+    fn save(&self, info: &SectorInfo) -> Result<()>;
+    fn load(&self, sector_id: u64) -> Result<Option<SectorInfo>>;
+    /// All saved sectors, ordered by sector number.
+    fn list(&self) -> Result<Vec<SectorInfo>>;
+    /// Append an entry to the event log of the sector.
+    fn append_log(&self, sector_id: u64, entry: &SectorLogEntry) -> Result<()>;
+    /// The event log of the sector, oldest entry first.
+    fn log(&self, sector_id: u64) -> Result<Vec<SectorLogEntry>>;
+    /// Delete the saved info and the event log of the sector.
+    fn remove(&self, sector_id: u64) -> Result<()>;
+}
+
+/// Force a saved sector without a running state machine into `state`, the
+/// update is logged with `reason` in the event log of the sector, and
+/// `observers` are notified of it.
+pub fn force_state(
+    store: &dyn SectorStore,
+    observers: &TransitionObservers,
+    sector_id: u64,
+    state: SectorState,
+    reason: &str,
+) -> Result<()> {
+    let mut info = store
+        .load(sector_id)?
+        .ok_or(EventError::UnknownSector(sector_id))?;
+    info.check_state(&state)?;
+    let from = std::mem::replace(&mut info.state, state.clone());
+    let duration = enter(&mut info);
+    store.save(&info)?;
+    let entry = SectorLogEntry::new(Some("UpdateState".to_string()), from.clone(), state, None)
+        .with_reason(Some(reason.to_string()));
+    store.append_log(sector_id, &entry)?;
+    observers.notify(sector_id, &from, &info.state, Some("UpdateState"), duration);
+    Ok(())
+}
+
+/// Move a saved sector without a running state machine to `to` as if it got
+/// `event`, the state machine resumed from `to` does the work of the event.
+/// Fails if `TRANSITIONS` has no such transition, `observers` are notified
+/// of the transition.
+pub fn request_transition(
+    store: &dyn SectorStore,
+    observers: &TransitionObservers,
+    sector_id: u64,
+    event: &'static str,
+    to: SectorState,
+) -> Result<()> {
+    let mut info = store
+        .load(sector_id)?
+        .ok_or(EventError::UnknownSector(sector_id))?;
+    if !is_allowed(&info.state, &to) {
+        return Err(EventError::IllegalEvent {
+            event,
+            state: info.state,
+        }
+        .into());
+    }
+    let from = std::mem::replace(&mut info.state, to.clone());
+    let duration = enter(&mut info);
+    store.save(&info)?;
+    let entry = SectorLogEntry::new(Some(event.to_string()), from.clone(), to, None);
+    store.append_log(sector_id, &entry)?;
+    observers.notify(sector_id, &from, &info.state, Some(event), duration);
+    Ok(())
+}
+
+/// Mark the sector entered its new state now, return how long it was in the
+/// old one, zero if that's unknown.
+fn enter(info: &mut SectorInfo) -> Duration {
+    let now = unix_time(SystemTime::now());
+    let entered = std::mem::replace(&mut info.state_entered, now);
+    info.stuck = false;
+    match entered {
+        0 => Duration::from_secs(0),
+        entered => Duration::from_secs(now.saturating_sub(entered)),
+    }
+}
+
+const INDEX_KEY: &str = "/index";
+
+fn sector_key(sector_id: u64) -> Key {
+    Key::new(&format!("/{}", sector_id))
+}
+
+fn log_len_key(sector_id: u64) -> Key {
+    Key::new(&format!("/{}/log", sector_id))
+}
+
+fn log_entry_key(sector_id: u64, seq: u64) -> Key {
+    Key::new(&format!("/{}/log/{}", sector_id, seq))
+}
+
+/// `SectorStore` in a datastore (namespace), every sector is saved as json
+/// under its number, and the list of sector numbers under `/index`. The
+/// event log of a sector is saved entry by entry under `<number>/log/<seq>`,
+/// with its length under `<number>/log`.
+pub struct DsSectorStore<DS: Batching> {
+    ds: DS,
+    index: Mutex<BTreeSet<u64>>,
+    log_lens: Mutex<HashMap<u64, u64>>,
+}
+
+impl<DS: Batching> DsSectorStore<DS> {
+    pub fn new(ds: DS) -> Result<Self> {
+        let key = Key::new(INDEX_KEY);
+        let index = if ds.has(&key)? {
+            serde_json::from_slice(&ds.get(&key)?)?
+        } else {
+            BTreeSet::new()
+        };
+        Ok(DsSectorStore {
+            ds,
+            index: Mutex::new(index),
+            log_lens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn saved_log_len(&self, sector_id: u64) -> Result<u64> {
+        let key = log_len_key(sector_id);
+        if !self.ds.has(&key)? {
+            return Ok(0);
+        }
+        Ok(serde_json::from_slice(&self.ds.get(&key)?)?)
+    }
+}
+
+impl<DS: Batching + Send + Sync> SectorStore for DsSectorStore<DS> {
+    fn save(&self, info: &SectorInfo) -> Result<()> {
+        self.ds
+            .put(sector_key(info.sector_id), serde_json::to_vec(info)?)?;
+        let mut index = self.index.lock().unwrap();
+        if index.insert(info.sector_id) {
+            self.ds
+                .put(Key::new(INDEX_KEY), serde_json::to_vec(&*index)?)?;
+        }
+        Ok(())
+    }
+
+    fn load(&self, sector_id: u64) -> Result<Option<SectorInfo>> {
+        let key = sector_key(sector_id);
+        if !self.ds.has(&key)? {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&self.ds.get(&key)?)?))
+    }
+
+    fn list(&self) -> Result<Vec<SectorInfo>> {
+        let index = self.index.lock().unwrap().clone();
+        let mut sectors = vec![];
+        for sector_id in index {
+            if let Some(info) = self.load(sector_id)? {
+                sectors.push(info);
+            }
+        }
+        Ok(sectors)
+    }
+
+    fn append_log(&self, sector_id: u64, entry: &SectorLogEntry) -> Result<()> {
+        let mut log_lens = self.log_lens.lock().unwrap();
+        let len = match log_lens.get(&sector_id) {
+            Some(len) => *len,
+            None => self.saved_log_len(sector_id)?,
+        };
+        self.ds
+            .put(log_entry_key(sector_id, len), serde_json::to_vec(entry)?)?;
+        self.ds
+            .put(log_len_key(sector_id), serde_json::to_vec(&(len + 1))?)?;
+        log_lens.insert(sector_id, len + 1);
+        Ok(())
+    }
+
+    fn log(&self, sector_id: u64) -> Result<Vec<SectorLogEntry>> {
+        let len = self.saved_log_len(sector_id)?;
+        let mut entries = Vec::with_capacity(len as usize);
+        for seq in 0..len {
+            entries.push(serde_json::from_slice(
+                &self.ds.get(&log_entry_key(sector_id, seq))?,
+            )?);
+        }
+        Ok(entries)
+    }
+
+    fn remove(&self, sector_id: u64) -> Result<()> {
+        let mut log_lens = self.log_lens.lock().unwrap();
+        let len = match log_lens.remove(&sector_id) {
+            Some(len) => len,
+            None => self.saved_log_len(sector_id)?,
+        };
+        for seq in 0..len {
+            self.ds.delete(&log_entry_key(sector_id, seq))?;
+        }
+        for key in &[log_len_key(sector_id), sector_key(sector_id)] {
+            if self.ds.has(key)? {
+                self.ds.delete(key)?;
+            }
+        }
+        let mut index = self.index.lock().unwrap();
+        if index.remove(&sector_id) {
+            self.ds
+                .put(Key::new(INDEX_KEY), serde_json::to_vec(&*index)?)?;
+        }
+        Ok(())
+    }
+}
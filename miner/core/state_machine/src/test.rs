@@ -1,10 +1,1187 @@
 // Copyright 2020 PolkaX
 
-use crate::{Event, EventError, EventRet, EventType, Planner, StateMachine, StateThread};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Result};
+use async_tools::task_manager::ServiceTaskExecutor;
+use cid::{Cid, Codec, IntoExt};
+use crossbeam::channel;
+use events::Events;
+use filecoin_proofs_api::{
+    seal::SealPreCommitPhase2Output, PieceInfo, RegisteredSealProof, UnpaddedBytesAmount,
+};
+use mock_chain::MockChain;
+use plum_sector::{RegisteredProof, SectorId};
+use specs_storage::{
+    Commit1Out, InteractiveSealRandomness, PreCommit1Out, Proof, SealRandomness, Sealer,
+};
+use stores::filetype::{SectorFileType, SectorFileTypes, SectorPaths};
+use stores::index::StorageId;
+use stores::traits::{FsStat, Store};
+
+use crate::{
+    is_allowed, register_seed_requests, transitions_dot, transitions_mermaid, DsSectorStore,
+    DurationHistogram, Event, EventError, EventRet, EventType, Handler, MsgLookup, PackerConfig,
+    PiecePacker, PiecePlacement, Planner, RetryPolicies, RetryPolicy, SealSeed, SealTicket,
+    SealingApi, SealingLimiter, SealingLimits, SectorBuilder, SectorInfo, SectorStart, SectorState,
+    SectorStore, SeedEpoch, SeedRequest, StateBudgets, StateGroup, StateMachine, StateThread,
+    StateUpdate, TransitionObserver, EXIT_CODE_OK, FAULT_MAX_AGE, INTERACTIVE_POREP_CONFIDENCE,
+    MAX_TICKET_AGE, PRE_COMMIT_CHALLENGE_DELAY, TRANSITIONS,
+};
+
+/// Fails all sealing work.
+struct FailingSealer;
+
+impl Sealer for FailingSealer {
+    fn seal_pre_commit1(
+        &mut self,
+        _sector: SectorId,
+        _ticket: SealRandomness,
+        _pieces: &[PieceInfo],
+    ) -> Result<PreCommit1Out> {
+        bail!("pc1 failed")
+    }
+    fn seal_pre_commit2(
+        &mut self,
+        _sector: SectorId,
+        _pc1o: PreCommit1Out,
+    ) -> Result<SealPreCommitPhase2Output> {
+        bail!("pc2 failed")
+    }
+    fn seal_commit1(
+        &mut self,
+        _sector: SectorId,
+        _ticket: SealRandomness,
+        _seed: InteractiveSealRandomness,
+        _pieces: &[PieceInfo],
+        _pco2: SealPreCommitPhase2Output,
+    ) -> Result<Commit1Out> {
+        bail!("c1 failed")
+    }
+    fn seal_commit2(&mut self, _sector: SectorId, _c1o: Commit1Out) -> Result<Proof> {
+        bail!("c2 failed")
+    }
+    fn finalize_sector(&mut self, _sector: SectorId) -> Result<()> {
+        bail!("finalize failed")
+    }
+}
+
+/// A `SealingApi` for tests, every call fails unless the test api
+/// overrides it.
+trait TestApi {
+    fn chain_head(&self) -> Result<u64> {
+        bail!("no chain")
+    }
+    fn ticket(&self, _sector: &SectorInfo) -> Result<(SealTicket, u64)> {
+        bail!("no chain")
+    }
+    fn send_pre_commit(&self, _sector: &SectorInfo) -> Result<Cid> {
+        bail!("no chain")
+    }
+    fn seed(&self, _sector: &SectorInfo, _epoch: u64) -> Result<SealSeed> {
+        bail!("no chain")
+    }
+    fn send_commit(&self, _sector: &SectorInfo) -> Result<Cid> {
+        bail!("no chain")
+    }
+    fn search_msg(&self, _msg: &Cid) -> Result<Option<MsgLookup>> {
+        bail!("no chain")
+    }
+    fn check_provable(&self, _sector: &SectorInfo) -> Result<bool> {
+        bail!("no chain")
+    }
+    fn send_fault_declaration(&self, _sector: &SectorInfo) -> Result<Cid> {
+        bail!("no chain")
+    }
+    fn send_recovery_declaration(&self, _sector: &SectorInfo) -> Result<Cid> {
+        bail!("no chain")
+    }
+    fn send_termination(&self, _sector: &SectorInfo) -> Result<Cid> {
+        bail!("no chain")
+    }
+}
+
+impl<T: TestApi> SealingApi for T {
+    fn chain_head(&self) -> Result<u64> {
+        TestApi::chain_head(self)
+    }
+    fn ticket(&self, sector: &SectorInfo) -> Result<(SealTicket, u64)> {
+        TestApi::ticket(self, sector)
+    }
+    fn send_pre_commit(&self, sector: &SectorInfo) -> Result<Cid> {
+        TestApi::send_pre_commit(self, sector)
+    }
+    fn seed(&self, sector: &SectorInfo, epoch: u64) -> Result<SealSeed> {
+        TestApi::seed(self, sector, epoch)
+    }
+    fn send_commit(&self, sector: &SectorInfo) -> Result<Cid> {
+        TestApi::send_commit(self, sector)
+    }
+    fn search_msg(&self, msg: &Cid) -> Result<Option<MsgLookup>> {
+        TestApi::search_msg(self, msg)
+    }
+    fn check_provable(&self, sector: &SectorInfo) -> Result<bool> {
+        TestApi::check_provable(self, sector)
+    }
+    fn send_fault_declaration(&self, sector: &SectorInfo) -> Result<Cid> {
+        TestApi::send_fault_declaration(self, sector)
+    }
+    fn send_recovery_declaration(&self, sector: &SectorInfo) -> Result<Cid> {
+        TestApi::send_recovery_declaration(self, sector)
+    }
+    fn send_termination(&self, sector: &SectorInfo) -> Result<Cid> {
+        TestApi::send_termination(self, sector)
+    }
+}
+
+struct FailingApi;
+
+impl TestApi for FailingApi {}
+
+/// Lands every message, sectors are provable when `provable` is set.
+struct FaultApi {
+    provable: Arc<AtomicBool>,
+}
+
+impl TestApi for FaultApi {
+    fn chain_head(&self) -> Result<u64> {
+        Ok(0)
+    }
+    fn search_msg(&self, _msg: &Cid) -> Result<Option<MsgLookup>> {
+        Ok(Some(MsgLookup {
+            exit_code: EXIT_CODE_OK,
+            height: 0,
+        }))
+    }
+    fn check_provable(&self, _sector: &SectorInfo) -> Result<bool> {
+        Ok(self.provable.load(Ordering::SeqCst))
+    }
+    fn send_fault_declaration(&self, sector: &SectorInfo) -> Result<Cid> {
+        Ok(msg_cid(b"fault", sector.sector_id))
+    }
+    fn send_recovery_declaration(&self, sector: &SectorInfo) -> Result<Cid> {
+        Ok(msg_cid(b"recovery", sector.sector_id))
+    }
+    fn send_termination(&self, sector: &SectorInfo) -> Result<Cid> {
+        Ok(msg_cid(b"termination", sector.sector_id))
+    }
+}
+
+/// Messages never land.
+struct PendingApi;
+
+impl TestApi for PendingApi {
+    fn search_msg(&self, _msg: &Cid) -> Result<Option<MsgLookup>> {
+        Ok(None)
+    }
+}
+
+/// Records the removed files of sectors, as `(sector number, file type)`.
+struct RemovedFiles {
+    removed: Arc<Mutex<Vec<(u64, String)>>>,
+}
+
+impl Store for RemovedFiles {
+    fn acquire_existing_sector(
+        &self,
+        _s: SectorId,
+        _single_type: SectorFileType,
+    ) -> (SectorPaths, SectorPaths) {
+        unreachable!()
+    }
+    fn acquire_alloc_sector(
+        &mut self,
+        _s: SectorId,
+        _spt: RegisteredProof,
+        _allocate: SectorFileType,
+        _sealing: bool,
+    ) -> stores::error::Result<(SectorPaths, SectorPaths)> {
+        unreachable!()
+    }
+    fn remove(&mut self, s: SectorId, single_type: SectorFileType) -> stores::error::Result<()> {
+        let file_type = SectorFileTypes::from(single_type).to_string();
+        self.removed.lock().unwrap().push((s.number, file_type));
+        Ok(())
+    }
+    fn move_storage(
+        &mut self,
+        _s: SectorId,
+        _spt: RegisteredProof,
+        _single_type: SectorFileType,
+    ) -> stores::error::Result<()> {
+        unreachable!()
+    }
+    fn fs_stat(&self, _id: StorageId) -> stores::error::Result<FsStat> {
+        unreachable!()
+    }
+}
+
+fn msg_cid(kind: &[u8], sector_id: u64) -> Cid {
+    let mut data = kind.to_vec();
+    data.extend_from_slice(&sector_id.to_be_bytes());
+    Cid::new_v1(Codec::Raw, multihash::Identity::digest(&data).into_ext())
+}
+
+/// Pre-commits land at `PRE_COMMIT_HEIGHT`, the seed drawn at an epoch is
+/// filled with the low byte of the epoch.
+struct SeedApi {
+    head: Arc<AtomicU64>,
+}
+
+const PRE_COMMIT_HEIGHT: u64 = 10;
+
+impl TestApi for SeedApi {
+    fn chain_head(&self) -> Result<u64> {
+        Ok(self.head.load(Ordering::SeqCst))
+    }
+    fn ticket(&self, _sector: &SectorInfo) -> Result<(SealTicket, u64)> {
+        Ok(([2; 32], self.head.load(Ordering::SeqCst)))
+    }
+    fn send_pre_commit(&self, sector: &SectorInfo) -> Result<Cid> {
+        Ok(msg_cid(b"pre-commit", sector.sector_id))
+    }
+    fn seed(&self, _sector: &SectorInfo, epoch: u64) -> Result<SealSeed> {
+        Ok([epoch as u8; 32])
+    }
+    fn search_msg(&self, _msg: &Cid) -> Result<Option<MsgLookup>> {
+        Ok(Some(MsgLookup {
+            exit_code: EXIT_CODE_OK,
+            height: PRE_COMMIT_HEIGHT,
+        }))
+    }
+    fn check_provable(&self, _sector: &SectorInfo) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// A builder of miner 1000 whose sealing always fails, with `api`.
+fn api_builder<A: SealingApi + Send + Sync + 'static>(api: A) -> SectorBuilder {
+    SectorBuilder::new(
+        1000,
+        RegisteredSealProof::StackedDrg2KiBV1,
+        Box::new(|| Box::new(FailingSealer)),
+        Box::new(api),
+    )
+}
+
+fn sector_builder() -> SectorBuilder {
+    api_builder(FailingApi)
+}
+
+fn new_store() -> Arc<dyn SectorStore> {
+    Arc::new(DsSectorStore::new(datastore::basic_ds::new_map_datastore()).unwrap())
+}
+
+/// Whether the sector is known to the group and in `state`.
+fn in_state(group: &StateGroup, sector_id: u64, state: SectorState) -> bool {
+    group
+        .state(sector_id)
+        .map(|info| info.state == state)
+        .unwrap_or(false)
+}
 
 #[test]
 fn exit_test() {
-    let state_thread = StateThread::run();
+    let mut state_thread = StateThread::run(Arc::new(sector_builder()), SectorInfo::new());
     let events = vec![Event::new(EventType::Exit)];
     state_thread.plan(&events);
+    assert!(state_thread.join(Duration::from_secs(5)));
+}
+
+fn packing(id: u64) -> Event {
+    Event::new(EventType::Packing(SectorStart {
+        id,
+        pieces: vec![PieceInfo {
+            commitment: [0; 32],
+            size: UnpaddedBytesAmount(2032),
+        }],
+    }))
+}
+
+/// Wait until `f` returns true, for at most 5 seconds.
+fn wait_until<F: Fn() -> bool>(f: F) -> bool {
+    for _ in 0..50 {
+        if f() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+#[test]
+fn group_routes_events_by_sector() {
+    let group = StateGroup::new(sector_builder());
+    group.plan(&[packing(1), packing(2)]);
+    // unknown sector and not a new one
+    group.send(3, Event::new(EventType::Exit));
+    assert_eq!(group.state(3), None);
+
+    let failed = |id| in_state(&group, id, SectorState::SealFailed);
+    assert!(wait_until(|| failed(1) && failed(2)));
+    let sectors = group.sectors();
+    assert_eq!(sectors.len(), 2);
+    assert_eq!(sectors[0].sector_id, 1);
+    assert_eq!(sectors[1].sector_id, 2);
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+#[test]
+fn restore_saved_sectors() {
+    let store = new_store();
+    let mut unsealed = SectorInfo::new();
+    unsealed.sector_id = 1;
+    unsealed.state = SectorState::Unsealed;
+    let mut proving = SectorInfo::new();
+    proving.sector_id = 2;
+    proving.state = SectorState::Proving;
+    store.save(&unsealed).unwrap();
+    store.save(&proving).unwrap();
+
+    let group = StateGroup::new(sector_builder().with_store(store.clone()));
+    // the proving sector is not resumed
+    assert_eq!(group.restore().unwrap(), 1);
+    assert_eq!(group.sectors().len(), 2);
+    assert_eq!(group.state(2), Some(proving));
+
+    // transitions of the resumed sector are saved
+    let saved_failed = || {
+        store
+            .load(1)
+            .unwrap()
+            .map(|info| info.state == SectorState::SealFailed)
+            .unwrap_or(false)
+    };
+    assert!(wait_until(saved_failed));
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+#[test]
+fn sector_event_log() {
+    let store = new_store();
+    let group = StateGroup::new(sector_builder().with_store(store));
+    group.send(1, packing(1));
+
+    assert!(wait_until(|| in_state(&group, 1, SectorState::SealFailed)));
+    let log = group.log(1).unwrap();
+    let transitions = log
+        .iter()
+        .map(|entry| (entry.event.as_deref(), entry.from.clone(), entry.to.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        transitions,
+        vec![
+            (Some("Packing"), SectorState::Empty, SectorState::Packing),
+            (None, SectorState::Packing, SectorState::Unsealed),
+            (None, SectorState::Unsealed, SectorState::SealFailed),
+        ]
+    );
+    assert_eq!(log[2].error.as_deref(), Some("no chain"));
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+#[test]
+fn retry_then_give_up() {
+    let mut policies = RetryPolicies::default();
+    policies.set(
+        SectorState::SealFailed,
+        RetryPolicy::new(2, Duration::from_millis(10), SectorState::Unsealed),
+    );
+    let group = StateGroup::new(sector_builder().with_retry_policies(policies));
+    group.send(1, packing(1));
+
+    assert!(wait_until(|| in_state(
+        &group,
+        1,
+        SectorState::FailedUnrecoverable
+    )));
+    let info = group.state(1).unwrap();
+    assert_eq!(info.retries.get(&SectorState::SealFailed), Some(&2));
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+#[test]
+fn retry_backoff() {
+    let policy = RetryPolicy::new(10, Duration::from_secs(1), SectorState::Unsealed);
+    assert_eq!(policy.backoff(0), Duration::from_secs(1));
+    assert_eq!(policy.backoff(3), Duration::from_secs(8));
+    assert_eq!(policy.backoff(5), Duration::from_secs(32));
+    assert_eq!(policy.backoff(6), Duration::from_secs(32));
+    assert_eq!(policy.backoff(100), Duration::from_secs(32));
+}
+
+#[test]
+fn transition_table() {
+    assert!(is_allowed(&SectorState::Empty, &SectorState::Packing));
+    assert!(is_allowed(&SectorState::SealFailed, &SectorState::Unsealed));
+    assert!(!is_allowed(&SectorState::Proving, &SectorState::Packing));
+    // every state could fall into `UndefinedSectorState`
+    assert!(is_allowed(
+        &SectorState::Proving,
+        &SectorState::UndefinedSectorState
+    ));
+
+    let dot = transitions_dot();
+    assert!(dot.starts_with("digraph SectorState {"));
+    assert!(dot.contains("    Empty -> Packing [label=\"Packing\"];"));
+    let mermaid = transitions_mermaid();
+    assert!(mermaid.contains("    CommitWait --> FinalizeSector: landed"));
+    assert_eq!(mermaid.lines().count(), TRANSITIONS.len() + 2);
+}
+
+#[test]
+fn parse_sector_states() {
+    let states = TRANSITIONS
+        .iter()
+        .flat_map(|t| vec![t.from.clone(), t.to.clone()])
+        .chain(Some(SectorState::UndefinedSectorState));
+    for state in states {
+        assert_eq!(format!("{:?}", state).parse::<SectorState>(), Ok(state));
+    }
+}
+
+#[test]
+fn reject_illegal_event() {
+    let mut info = SectorInfo::new();
+    info.sector_id = 1;
+    info.state = SectorState::Proving;
+    let (sender, receiver) = channel::unbounded();
+    let mut state_machine = StateMachine::new(
+        sender,
+        receiver,
+        Arc::new(sector_builder()),
+        Arc::new(RwLock::new(info)),
+    );
+    let ret = packing(1).handle(&mut state_machine);
+    assert_eq!(
+        ret,
+        Err(EventError::IllegalEvent {
+            event: "Packing",
+            state: SectorState::Proving,
+        })
+    );
+}
+
+#[test]
+fn manual_state_update() {
+    let store = new_store();
+    let group = StateGroup::new(sector_builder().with_store(store.clone()));
+    group.send(1, packing(1));
+    assert!(wait_until(|| in_state(&group, 1, SectorState::SealFailed)));
+
+    let err = group
+        .update_state(1, SectorState::WaitSeed, "skip sealing")
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<EventError>(),
+        Some(&EventError::MissingField {
+            state: SectorState::WaitSeed,
+            field: "commr and commd",
+        })
+    );
+    let err = group
+        .update_state(2, SectorState::Packing, "no such sector")
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<EventError>(),
+        Some(&EventError::UnknownSector(2))
+    );
+    assert_eq!(
+        "Sealing".parse::<SectorState>(),
+        Err(EventError::UnknownState("Sealing".to_string()))
+    );
+
+    group
+        .update_state(1, SectorState::FailedUnrecoverable, "bad disk")
+        .unwrap();
+    assert!(wait_until(|| in_state(
+        &group,
+        1,
+        SectorState::FailedUnrecoverable
+    )));
+    let log = group.log(1).unwrap();
+    let last = log.last().unwrap();
+    assert_eq!(last.event.as_deref(), Some("UpdateState"));
+    assert_eq!(last.from, SectorState::SealFailed);
+    assert_eq!(last.reason.as_deref(), Some("bad disk"));
+    assert!(group.shutdown(Duration::from_secs(5)).is_empty());
+
+    // a saved sector without a state machine is resumed from the new state
+    let recorder = Arc::new(TransitionRecorder::default());
+    let group = StateGroup::new(
+        sector_builder()
+            .with_store(store)
+            .with_observer(recorder.clone()),
+    );
+    group
+        .update_state(1, SectorState::Packing, "retry sealing")
+        .unwrap();
+    assert_eq!(
+        recorder.transitions.lock().unwrap()[0],
+        (
+            1,
+            SectorState::FailedUnrecoverable,
+            SectorState::Packing,
+            Some("UpdateState".to_string())
+        )
+    );
+    assert!(wait_until(|| in_state(&group, 1, SectorState::SealFailed)));
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+#[test]
+fn graceful_shutdown() {
+    let store = new_store();
+    let group = StateGroup::new(sector_builder().with_store(store.clone()));
+    group.send(1, packing(1));
+    group.send(2, packing(2));
+    assert!(group.shutdown(Duration::from_secs(5)).is_empty());
+    assert!(group.running().is_empty());
+
+    // the packing events were applied and saved before stopping
+    for id in 1..=2 {
+        let info = store.load(id).unwrap().unwrap();
+        assert_ne!(info.state, SectorState::Empty);
+        assert_eq!(group.state(id), Some(info));
+    }
+}
+
+#[test]
+fn resume_stopped_state_machines() {
+    let group = StateGroup::new(sector_builder().with_store(new_store()));
+    group.send(1, packing(1));
+    assert!(wait_until(|| in_state(&group, 1, SectorState::SealFailed)));
+    group.plan(&[Event::new(EventType::Exit)]);
+    assert!(wait_until(|| group.running().is_empty()));
+    assert!(in_state(&group, 1, SectorState::SealFailed));
+
+    // resumed from the saved sector by its next event
+    let update = StateUpdate {
+        id: 1,
+        state: SectorState::Packing,
+        reason: "seal again".to_string(),
+    };
+    group.send(1, Event::new(EventType::UpdateState(update)));
+    assert_eq!(group.running(), vec![1]);
+    assert!(wait_until(|| group.log(1).unwrap().len() == 6));
+    assert!(in_state(&group, 1, SectorState::SealFailed));
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+#[test]
+fn fault_lifecycle() {
+    let store = new_store();
+    let mut proving = SectorInfo::new();
+    proving.sector_id = 1;
+    proving.state = SectorState::Proving;
+    store.save(&proving).unwrap();
+
+    let provable = Arc::new(AtomicBool::new(false));
+    let sb = api_builder(FaultApi {
+        provable: provable.clone(),
+    })
+    .with_store(store);
+    let group = StateGroup::new(sb);
+
+    group.check_faults(100);
+    assert!(wait_until(|| in_state(
+        &group,
+        1,
+        SectorState::FaultReported
+    )));
+    let info = group.state(1).unwrap();
+    assert_eq!(info.fault_epoch, 100);
+    assert_eq!(info.fault_report_msg, msg_cid(b"fault", 1));
+
+    provable.store(true, Ordering::SeqCst);
+    group.check_faults(200);
+    assert!(wait_until(|| in_state(&group, 1, SectorState::Proving)));
+    assert_eq!(
+        group.state(1).unwrap().recovery_msg,
+        msg_cid(b"recovery", 1)
+    );
+    // the declaration is waited for outside the event handler
+    let to_states = group
+        .log(1)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.to)
+        .collect::<Vec<_>>();
+    assert!(to_states.ends_with(&[
+        SectorState::Recovering,
+        SectorState::RecoveryWait,
+        SectorState::Proving
+    ]));
+
+    provable.store(false, Ordering::SeqCst);
+    group.check_faults(300);
+    assert!(wait_until(|| in_state(
+        &group,
+        1,
+        SectorState::FaultReported
+    )));
+    // not expired yet
+    group.check_faults(300 + FAULT_MAX_AGE);
+    assert!(in_state(&group, 1, SectorState::FaultReported));
+    group.check_faults(301 + FAULT_MAX_AGE);
+    assert!(wait_until(|| in_state(
+        &group,
+        1,
+        SectorState::FaultedFinal
+    )));
+    let log = group.log(1).unwrap();
+    assert_eq!(log.last().unwrap().event.as_deref(), Some("FaultExpired"));
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+fn piece(size: u64) -> PieceInfo {
+    PieceInfo {
+        commitment: [1; 32],
+        size: UnpaddedBytesAmount(size),
+    }
+}
+
+fn filler(size: u64) -> PieceInfo {
+    PieceInfo {
+        commitment: [0; 32],
+        size: UnpaddedBytesAmount(size),
+    }
+}
+
+#[test]
+fn pack_deal_pieces() {
+    let config = PackerConfig {
+        sector_size: 2048,
+        max_wait: Duration::from_secs(60),
+        max_open: 0,
+    };
+    let mut packer = PiecePacker::new(
+        config,
+        1,
+        Box::new(|size| {
+            Ok(PieceInfo {
+                commitment: [0; 32],
+                size,
+            })
+        }),
+    );
+    let start = Instant::now();
+    let place = |sector_id, offset| PiecePlacement { sector_id, offset };
+
+    assert_eq!(packer.add_piece(piece(1016), start).unwrap(), place(1, 0));
+    assert_eq!(packer.add_piece(piece(254), start).unwrap(), place(1, 1024));
+    // no aligned room left in sector 1
+    assert_eq!(packer.add_piece(piece(1016), start).unwrap(), place(2, 0));
+    assert_eq!(packer.add_piece(piece(127), start).unwrap(), place(1, 1280));
+    assert!(packer.add_piece(piece(100), start).is_err());
+    assert!(packer.add_piece(piece(4064), start).is_err());
+
+    assert!(packer.ready(start).unwrap().is_empty());
+    let events = packer.ready(start + Duration::from_secs(60)).unwrap();
+    let sectors = events
+        .iter()
+        .map(|event| match event.event_type() {
+            EventType::Packing(sector_start) => sector_start.clone(),
+            _ => panic!("unexpected event {:?}", event),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(sectors.len(), 2);
+    assert_eq!(sectors[0].id, 1);
+    assert_eq!(
+        sectors[0].pieces,
+        vec![
+            piece(1016),
+            piece(254),
+            piece(127),
+            filler(127),
+            filler(508)
+        ]
+    );
+    assert_eq!(sectors[1].id, 2);
+    assert_eq!(sectors[1].pieces, vec![piece(1016), filler(1016)]);
+
+    // a full sector is sealed without waiting
+    packer.add_piece(piece(2032), start).unwrap();
+    assert_eq!(packer.ready(start).unwrap().len(), 1);
+    assert!(packer.flush().unwrap().is_empty());
+}
+
+/// A sector with its pre-commit data, ready to send the pre-commit.
+fn pre_committing(id: u64, ticket_epoch: u64) -> SectorInfo {
+    let mut info = SectorInfo::new();
+    info.sector_id = id;
+    info.state = SectorState::PreCommitting;
+    info.pieces = vec![piece(2032)];
+    info.commr = [1; 32];
+    info.commd = [1; 32];
+    info.ticket_epoch = ticket_epoch;
+    info
+}
+
+/// A group resuming `sectors`, sending seed requests to `seed_requests`.
+fn seed_group(
+    sectors: &[SectorInfo],
+    head: Arc<AtomicU64>,
+    seed_requests: channel::Sender<SeedRequest>,
+) -> StateGroup {
+    let store = new_store();
+    for info in sectors {
+        store.save(info).unwrap();
+    }
+    let sb = api_builder(SeedApi { head })
+        .with_store(store)
+        .with_seed_watcher(Box::new(seed_requests));
+    let group = StateGroup::new(sb);
+    assert_eq!(group.restore().unwrap(), sectors.len());
+    group
+}
+
+fn log_transitions(group: &StateGroup, sector_id: u64) -> Vec<(SectorState, SectorState)> {
+    group
+        .log(sector_id)
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.from, entry.to))
+        .collect()
+}
+
+#[test]
+fn redraw_expired_ticket() {
+    let head = Arc::new(AtomicU64::new(MAX_TICKET_AGE + 1));
+    let (seed_requests, _) = channel::unbounded();
+    let group = seed_group(&[pre_committing(1, 0)], head, seed_requests);
+
+    // PC1 is redone with a new ticket, and fails in the test sealer
+    assert!(wait_until(|| in_state(&group, 1, SectorState::SealFailed)));
+    assert_eq!(
+        log_transitions(&group, 1)[..2],
+        [
+            (SectorState::PreCommitting, SectorState::Unsealed),
+            (SectorState::Unsealed, SectorState::SealFailed),
+        ]
+    );
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+fn events_executor() -> ServiceTaskExecutor {
+    Arc::new(|fut| {
+        async_std::task::spawn(fut);
+    })
+}
+
+#[test]
+fn keep_failed_seed_requests() {
+    let chain = Arc::new(MockChain::new());
+    let (events, _listen) = Events::new(chain.clone(), events_executor());
+    let mut events = events.write().unwrap();
+    let (sender, requests) = channel::unbounded();
+    let (notify, _) = channel::unbounded();
+    let request = SeedRequest {
+        sector_id: 1,
+        seed_epoch: 1,
+        notify,
+    };
+    sender.send(request).unwrap();
+
+    // no head to register triggers on yet
+    let mut pending = vec![];
+    assert_eq!(
+        register_seed_requests(&mut events, &requests, &mut pending),
+        0
+    );
+    assert_eq!(pending.len(), 1);
+    events.head_change(vec![], vec![chain.head()]).unwrap();
+    assert_eq!(
+        register_seed_requests(&mut events, &requests, &mut pending),
+        1
+    );
+    assert!(pending.is_empty());
+}
+
+#[test]
+fn wait_seed_with_confidence() {
+    let chain = Arc::new(MockChain::new());
+    let (events, _listen) = Events::new(chain.clone(), events_executor());
+    let mut events = events.write().unwrap();
+    events.head_change(vec![], vec![chain.head()]).unwrap();
+
+    let head = Arc::new(AtomicU64::new(0));
+    let (seed_requests, requests) = channel::unbounded();
+    let group = seed_group(&[pre_committing(1, 0)], head, seed_requests);
+
+    let seed_epoch = PRE_COMMIT_HEIGHT + PRE_COMMIT_CHALLENGE_DELAY;
+    assert!(wait_until(|| !requests.is_empty()));
+    let mut pending = vec![];
+    assert_eq!(
+        register_seed_requests(&mut events, &requests, &mut pending),
+        1
+    );
+    assert!(wait_until(
+        || group.state(1).unwrap().seed_epoch == seed_epoch
+    ));
+    assert!(in_state(&group, 1, SectorState::WaitSeed));
+
+    // the seed is drawn once the seed epoch got the confidence, the commit
+    // fails in the test sealer
+    let tipsets = chain.append_n((seed_epoch + INTERACTIVE_POREP_CONFIDENCE - 1) as usize);
+    events.head_change(vec![], tipsets).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(in_state(&group, 1, SectorState::WaitSeed));
+    events.head_change(vec![], vec![chain.append()]).unwrap();
+    assert!(wait_until(|| in_state(
+        &group,
+        1,
+        SectorState::SealCommitFailed
+    )));
+    assert_eq!(group.state(1).unwrap().seed, [seed_epoch as u8; 32]);
+
+    // reverting the seed epoch goes back to waiting, until it's on chain
+    // again with the confidence
+    let depth = INTERACTIVE_POREP_CONFIDENCE as usize + 1;
+    let (reverts, applies) = chain.reorg(depth, depth);
+    events.head_change(reverts, applies).unwrap();
+    assert!(wait_until(|| {
+        log_transitions(&group, 1)
+            .iter()
+            .filter(|(from, to)| {
+                *from == SectorState::SealCommitFailed && *to == SectorState::WaitSeed
+            })
+            .count()
+            == 1
+    }));
+    assert!(wait_until(|| in_state(
+        &group,
+        1,
+        SectorState::SealCommitFailed
+    )));
+    let seed_readies = group
+        .log(1)
+        .unwrap()
+        .iter()
+        .filter(|entry| entry.event.as_deref() == Some("SeedReady"))
+        .count();
+    assert_eq!(seed_readies, 2);
+
+    // a revert from a trigger of a previous pre-commit is ignored
+    let stale = SeedEpoch {
+        id: 1,
+        epoch: seed_epoch - 1,
+    };
+    group.send(1, Event::new(EventType::SeedReverted(stale)));
+    assert!(wait_until(|| {
+        group.log(1).unwrap().last().unwrap().event.as_deref() == Some("SeedReverted")
+    }));
+    assert!(in_state(&group, 1, SectorState::SealCommitFailed));
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+#[test]
+fn sealing_limits() {
+    let mut limits = SealingLimits::default();
+    limits.max_sealing = 1;
+    let store = new_store();
+    let group = StateGroup::new(
+        sector_builder()
+            .with_store(store.clone())
+            .with_limits(limits.clone()),
+    );
+
+    group.send(1, packing(1));
+    assert!(wait_until(|| in_state(&group, 1, SectorState::SealFailed)));
+    // sector 1 is still sealing, waiting for its retry
+    group.send(2, packing(2));
+    let mut queued = BTreeMap::new();
+    queued.insert(SectorState::Packing, 1);
+    assert!(wait_until(|| group.queued() == queued));
+    assert!(in_state(&group, 2, SectorState::Empty));
+    assert!(group.shutdown(Duration::from_secs(5)).is_empty());
+
+    // the queued transition is saved, and still queued after a restart
+    let saved = store.load(2).unwrap().unwrap();
+    assert_eq!(saved.queued, Some((SectorState::Packing, None)));
+    let group = StateGroup::new(sector_builder().with_store(store).with_limits(limits));
+    assert_eq!(group.restore().unwrap(), 2);
+    assert!(wait_until(|| group.queued() == queued));
+
+    group
+        .update_state(1, SectorState::FailedUnrecoverable, "make room")
+        .unwrap();
+    assert!(wait_until(|| in_state(&group, 2, SectorState::SealFailed)));
+    assert!(group.queued().is_empty());
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+#[test]
+fn limiter_drops_removed_sectors() {
+    let limiter = SealingLimiter::default();
+    limiter.update(1, &SectorState::Proving);
+    limiter.update(2, &SectorState::Removing);
+    assert!(limiter.try_enter(2, &SectorState::Removed));
+    let mut counts = BTreeMap::new();
+    counts.insert(SectorState::Proving, 1);
+    assert_eq!(limiter.counts(), counts);
+}
+
+#[test]
+fn pack_with_max_open_sectors() {
+    let config = PackerConfig {
+        sector_size: 2048,
+        max_wait: Duration::from_secs(60),
+        max_open: 1,
+    };
+    let mut packer = PiecePacker::new(
+        config,
+        1,
+        Box::new(|size| {
+            Ok(PieceInfo {
+                commitment: [0; 32],
+                size,
+            })
+        }),
+    );
+    let start = Instant::now();
+    packer.add_piece(piece(1016), start).unwrap();
+    packer.add_piece(piece(1016), start).unwrap();
+    // opening sector 2 and 3 closes sector 1 and 2 early
+    packer.add_piece(piece(254), start).unwrap();
+    packer.add_piece(piece(2032), start).unwrap();
+    let sector_ids = packer
+        .ready(start)
+        .unwrap()
+        .iter()
+        .filter_map(|event| event.sector_id())
+        .collect::<Vec<_>>();
+    assert_eq!(sector_ids, vec![1, 2, 3]);
+}
+
+#[test]
+fn terminate_and_remove_sectors() {
+    let store = new_store();
+    let mut proving = SectorInfo::new();
+    proving.sector_id = 1;
+    proving.state = SectorState::Proving;
+    let mut failed = SectorInfo::new();
+    failed.sector_id = 2;
+    failed.state = SectorState::FailedUnrecoverable;
+    store.save(&proving).unwrap();
+    store.save(&failed).unwrap();
+
+    let removed = Arc::new(Mutex::new(vec![]));
+    let sb = api_builder(FaultApi {
+        provable: Arc::new(AtomicBool::new(true)),
+    })
+    .with_store(store.clone())
+    .with_sector_files(Arc::new(Mutex::new(RemovedFiles {
+        removed: removed.clone(),
+    })));
+    let group = StateGroup::new(sb);
+    let is_removed = |sector_id| in_state(&group, sector_id, SectorState::Removed);
+
+    // a proving sector could not be removed without terminating it
+    assert!(group.remove(1).is_err());
+    assert!(group.terminate(2).is_err());
+    assert!(group.remove(3).is_err());
+
+    group.terminate(1).unwrap();
+    assert!(wait_until(|| is_removed(1)));
+    group.remove(2).unwrap();
+    assert!(wait_until(|| is_removed(2)));
+
+    let file_types = |sector_id| {
+        vec![
+            (sector_id, "unsealed".to_string()),
+            (sector_id, "sealed".to_string()),
+            (sector_id, "cache".to_string()),
+        ]
+    };
+    let mut expected = file_types(1);
+    expected.extend(file_types(2));
+    assert_eq!(*removed.lock().unwrap(), expected);
+    assert!(store.list().unwrap().is_empty());
+    assert!(store.log(1).unwrap().is_empty());
+    // the state machines of removed sectors are stopped and dropped
+    assert!(group.running().is_empty());
+    assert_eq!(group.state(1), None);
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+/// Records all transitions, without their durations.
+#[derive(Default)]
+struct TransitionRecorder {
+    transitions: Mutex<Vec<(u64, SectorState, SectorState, Option<String>)>>,
+}
+
+impl TransitionObserver for TransitionRecorder {
+    fn on_transition(
+        &self,
+        sector_id: u64,
+        from: &SectorState,
+        to: &SectorState,
+        event: Option<&str>,
+        _duration: Duration,
+    ) {
+        self.transitions.lock().unwrap().push((
+            sector_id,
+            from.clone(),
+            to.clone(),
+            event.map(str::to_string),
+        ));
+    }
+}
+
+#[test]
+fn observe_transitions() {
+    let recorder = Arc::new(TransitionRecorder::default());
+    let histogram = Arc::new(DurationHistogram::default());
+    let group = StateGroup::new(sector_builder().with_observer(recorder.clone()));
+    group.add_observer(histogram.clone());
+
+    group.send(1, packing(1));
+    assert!(wait_until(
+        || recorder.transitions.lock().unwrap().len() == 3
+    ));
+    assert_eq!(
+        *recorder.transitions.lock().unwrap(),
+        vec![
+            (
+                1,
+                SectorState::Empty,
+                SectorState::Packing,
+                Some("Packing".to_string())
+            ),
+            (1, SectorState::Packing, SectorState::Unsealed, None),
+            (1, SectorState::Unsealed, SectorState::SealFailed, None),
+        ]
+    );
+    let states = histogram.snapshot();
+    assert_eq!(
+        states.keys().cloned().collect::<Vec<_>>(),
+        vec![
+            SectorState::Empty,
+            SectorState::Packing,
+            SectorState::Unsealed
+        ]
+    );
+    for histogram in states.values() {
+        assert_eq!(histogram.count, 1);
+        // all within a second
+        assert_eq!(histogram.buckets[0], 1);
+    }
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+#[test]
+fn detect_stuck_sectors() {
+    let head = Arc::new(AtomicU64::new(0));
+    let (seed_requests, _requests) = channel::unbounded();
+    let group = seed_group(&[pre_committing(1, 0)], head.clone(), seed_requests.clone());
+    let waiting = |group: &StateGroup| {
+        group
+            .state(1)
+            .map(|info| info.state == SectorState::WaitSeed && info.seed_epoch != 0)
+            .unwrap_or(false)
+    };
+    assert!(wait_until(|| waiting(&group)));
+
+    // the seed never comes, the sector is only marked by default budgets
+    let later = SystemTime::now() + Duration::from_secs(7 * 3600);
+    assert!(group.check_stuck(SystemTime::now()).is_empty());
+    assert_eq!(group.check_stuck(later), vec![1]);
+    assert!(wait_until(|| group.state(1).unwrap().stuck));
+    assert_eq!(group.state(1).unwrap().state, SectorState::WaitSeed);
+    assert!(group.check_stuck(later).is_empty());
+    let log = group.log(1).unwrap();
+    assert_eq!(log.last().unwrap().event.as_deref(), Some("Stuck"));
+    group.plan(&[Event::new(EventType::Exit)]);
+
+    // with retry the pre-commit is failed, to be sent again
+    let store = new_store();
+    store.save(&pre_committing(1, 0)).unwrap();
+    let mut budgets = StateBudgets::none();
+    budgets.set(SectorState::WaitSeed, Duration::from_secs(60));
+    budgets.retry = true;
+    let sb = api_builder(SeedApi { head })
+        .with_store(store)
+        .with_seed_watcher(Box::new(seed_requests))
+        .with_state_budgets(budgets);
+    let group = StateGroup::new(sb);
+    assert_eq!(group.restore().unwrap(), 1);
+    assert!(wait_until(|| waiting(&group)));
+
+    let later = SystemTime::now() + Duration::from_secs(120);
+    assert_eq!(group.check_stuck(later), vec![1]);
+    assert!(wait_until(|| {
+        group
+            .state(1)
+            .map(|info| info.state == SectorState::PreCommitFailed && !info.stuck)
+            .unwrap_or(false)
+    }));
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+#[test]
+fn old_sectors_not_stuck_on_restore() {
+    // saved without the time it entered its state
+    let store = new_store();
+    let mut faulty = SectorInfo::new();
+    faulty.sector_id = 1;
+    faulty.state = SectorState::FaultReported;
+    store.save(&faulty).unwrap();
+
+    let mut budgets = StateBudgets::none();
+    budgets.set(SectorState::FaultReported, Duration::from_secs(60));
+    let sb = api_builder(FaultApi {
+        provable: Arc::new(AtomicBool::new(false)),
+    })
+    .with_store(store)
+    .with_state_budgets(budgets);
+    let group = StateGroup::new(sb);
+    assert_eq!(group.restore().unwrap(), 1);
+    assert!(wait_until(|| group.state(1).unwrap().state_entered != 0));
+
+    assert!(group.check_stuck(SystemTime::now()).is_empty());
+    let later = SystemTime::now() + Duration::from_secs(120);
+    assert_eq!(group.check_stuck(later), vec![1]);
+
+    group.plan(&[Event::new(EventType::Exit)]);
+}
+
+#[test]
+fn detect_stuck_message_waits() {
+    let store = new_store();
+    let mut committed = SectorInfo::new();
+    committed.sector_id = 1;
+    committed.state = SectorState::CommitWait;
+    committed.commit_msg = msg_cid(b"commit", 1);
+    store.save(&committed).unwrap();
+
+    let group = StateGroup::new(api_builder(PendingApi).with_store(store));
+    assert_eq!(group.restore().unwrap(), 1);
+    assert!(wait_until(|| group.state(1).unwrap().state_entered != 0));
+
+    // the commit never lands, the sector still handles events meanwhile
+    let later = SystemTime::now() + Duration::from_secs(7 * 3600);
+    assert_eq!(group.check_stuck(later), vec![1]);
+    assert!(wait_until(|| group.state(1).unwrap().stuck));
+    assert!(group.check_stuck(later).is_empty());
+    assert!(in_state(&group, 1, SectorState::CommitWait));
+
+    group.plan(&[Event::new(EventType::Exit)]);
 }
@@ -1,35 +1,113 @@
 // Copyright 2020 PolkaX
 
-use crate::{Event, Planner, StateMachine};
-use crossbeam::deque::Worker;
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::Duration;
 
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use log::{error, warn};
+
+use crate::{
+    Event, EventType, Planner, SectorBuilder, SectorInfo, SectorState, StateMachine, TARGET,
+};
+
+/// Thread running the state machine of one sector. Dropping it stops the
+/// state machine after its current work, without waiting for it.
 pub struct StateThread {
-    worker: Worker<Event>,
-    join_handle: thread::JoinHandle<()>,
+    events: Sender<Event>,
+    info: Arc<RwLock<SectorInfo>>,
+    // closed when the state machine stopped
+    done: Receiver<()>,
+    join_handle: Option<thread::JoinHandle<()>>,
 }
 
 impl Planner for StateThread {
     fn plan(&self, events: &[Event]) {
         for event in events {
-            self.worker.push(event.clone());
+            if self.events.send(event.clone()).is_err() {
+                warn!(
+                    target: TARGET,
+                    "drop event {:?}, the state machine is stopped", event
+                );
+            }
         }
     }
 }
 
 impl StateThread {
-    pub fn run() -> Self {
-        let worker = Worker::<Event>::new_fifo();
-        let stealer = worker.stealer();
+    /// Run a state machine starting from `info`.
+    pub fn run(sb: Arc<SectorBuilder>, info: SectorInfo) -> Self {
+        let (events, receiver) = channel::unbounded();
+        let (done_tx, done) = channel::bounded::<()>(0);
+        let info = Arc::new(RwLock::new(info));
 
-        let join_handle: thread::JoinHandle<_> = thread::spawn(|| {
-            let mut state_machine = StateMachine::new(stealer);
+        let shared = info.clone();
+        let sender = events.clone();
+        let join_handle = thread::spawn(move || {
+            let _done = done_tx;
+            let mut state_machine = StateMachine::new(sender, receiver, sb, shared);
             state_machine.run();
         });
 
         StateThread {
-            worker,
-            join_handle,
+            events,
+            info,
+            done,
+            join_handle: Some(join_handle),
+        }
+    }
+
+    /// The latest sector info of the state machine.
+    pub fn info(&self) -> SectorInfo {
+        self.info.read().unwrap().clone()
+    }
+
+    /// The latest state of the sector.
+    pub fn state(&self) -> SectorState {
+        self.info.read().unwrap().state.clone()
+    }
+
+    /// Whether the state machine stopped, e.g. by `Exit` or a panic.
+    pub fn is_stopped(&self) -> bool {
+        self.done.try_recv() == Err(TryRecvError::Disconnected)
+    }
+
+    /// Ask the state machine to stop after its current work, events sent
+    /// before are still applied.
+    pub fn shutdown(&self) {
+        self.plan(&[Event::new(EventType::Exit)]);
+    }
+
+    /// Wait at most `timeout` for the state machine to stop, return whether
+    /// it stopped.
+    pub fn join(&mut self, timeout: Duration) -> bool {
+        let join_handle = match self.join_handle.take() {
+            Some(join_handle) => join_handle,
+            None => return true,
+        };
+        match self.done.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                self.join_handle = Some(join_handle);
+                false
+            }
+            _ => {
+                if join_handle.join().is_err() {
+                    error!(
+                        target: TARGET,
+                        "state machine of sector {} panicked",
+                        self.info().sector_id
+                    );
+                }
+                true
+            }
         }
     }
 }
+
+impl Drop for StateThread {
+    fn drop(&mut self) {
+        // the state machine holds a sender itself, so it's not stopped by
+        // closing the channel
+        let _ = self.events.send(Event::new(EventType::Exit));
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2020 PolkaX
+
+use std::fmt::Write;
+
+use crate::SectorState;
+
+/// An allowed transition of a sector.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transition {
+    pub from: SectorState,
+    pub to: SectorState,
+    /// what makes the transition, an event name or the outcome of the work
+    /// of `from`
+    pub label: &'static str,
+}
+
+const fn t(from: SectorState, to: SectorState, label: &'static str) -> Transition {
+    Transition { from, to, label }
+}
+
+use SectorState::*;
+
+/// All allowed transitions, the state machine rejects everything else.
+/// Any state could also fall into `UndefinedSectorState`, which is left by
+/// manual state updates only.
+pub const TRANSITIONS: &[Transition] = &[
+    t(Empty, Packing, "Packing"),
+    t(Packing, Unsealed, "packed"),
+    t(Packing, PackingFailed, "failed"),
+    t(Unsealed, PreCommitting, "sealed"),
+    t(Unsealed, SealFailed, "failed"),
+    t(PreCommitting, WaitSeed, "sent"),
+    t(PreCommitting, PreCommitFailed, "failed"),
+    t(PreCommitting, Unsealed, "ticket expired"),
+    t(WaitSeed, Committing, "SeedReady"),
+    t(WaitSeed, PreCommitFailed, "failed"),
+    t(Committing, CommitWait, "sent"),
+    t(Committing, SealCommitFailed, "failed"),
+    t(Committing, CommitFailed, "send failed"),
+    t(CommitWait, FinalizeSector, "landed"),
+    t(CommitWait, CommitFailed, "failed"),
+    t(Committing, WaitSeed, "SeedReverted"),
+    t(CommitWait, WaitSeed, "SeedReverted"),
+    t(SealCommitFailed, WaitSeed, "SeedReverted"),
+    t(CommitFailed, WaitSeed, "SeedReverted"),
+    t(FinalizeSector, Proving, "finalized"),
+    t(FinalizeSector, FailedUnrecoverable, "failed"),
+    // faults
+    t(Proving, Faulty, "FaultDetected"),
+    t(Faulty, FaultReported, "declared"),
+    t(Faulty, FaultReportFailed, "failed"),
+    t(FaultReported, Recovering, "FaultRecovered"),
+    t(Recovering, RecoveryWait, "sent"),
+    t(Recovering, RecoveryFailed, "failed"),
+    t(RecoveryWait, Proving, "landed"),
+    t(RecoveryWait, RecoveryFailed, "failed"),
+    t(FaultReported, FaultedFinal, "FaultExpired"),
+    // termination and removal
+    t(Proving, Terminating, "Terminate"),
+    t(FaultReported, Terminating, "Terminate"),
+    t(Terminating, TerminateWait, "sent"),
+    t(Terminating, TerminateFailed, "failed"),
+    t(TerminateWait, Removing, "landed"),
+    t(TerminateWait, TerminateFailed, "failed"),
+    t(PackingFailed, Removing, "Remove"),
+    t(SealFailed, Removing, "Remove"),
+    t(PreCommitFailed, Removing, "Remove"),
+    t(SealCommitFailed, Removing, "Remove"),
+    t(CommitFailed, Removing, "Remove"),
+    t(FailedUnrecoverable, Removing, "Remove"),
+    t(FaultedFinal, Removing, "Remove"),
+    t(UndefinedSectorState, Removing, "Remove"),
+    t(Removing, Removed, "removed"),
+    t(Removing, RemoveFailed, "failed"),
+    // retries
+    t(PackingFailed, Packing, "retry"),
+    t(SealFailed, Unsealed, "retry"),
+    t(PreCommitFailed, PreCommitting, "retry"),
+    t(SealCommitFailed, Committing, "retry"),
+    t(CommitFailed, Committing, "retry"),
+    t(FaultReportFailed, Faulty, "retry"),
+    t(RecoveryFailed, Recovering, "retry"),
+    t(TerminateFailed, Terminating, "retry"),
+    t(RemoveFailed, Removing, "retry"),
+    t(PackingFailed, FailedUnrecoverable, "give up"),
+    t(SealFailed, FailedUnrecoverable, "give up"),
+    t(PreCommitFailed, FailedUnrecoverable, "give up"),
+    t(SealCommitFailed, FailedUnrecoverable, "give up"),
+    t(CommitFailed, FailedUnrecoverable, "give up"),
+    t(FaultReportFailed, FailedUnrecoverable, "give up"),
+    t(RecoveryFailed, FailedUnrecoverable, "give up"),
+    t(TerminateFailed, FailedUnrecoverable, "give up"),
+    t(RemoveFailed, FailedUnrecoverable, "give up"),
+];
+
+/// Whether a sector could go from `from` to `to`.
+pub fn is_allowed(from: &SectorState, to: &SectorState) -> bool {
+    *to == UndefinedSectorState
+        || TRANSITIONS
+            .iter()
+            .any(|transition| transition.from == *from && transition.to == *to)
+}
+
+/// The transition table as a Graphviz DOT digraph.
+pub fn transitions_dot() -> String {
+    let mut dot = String::from("digraph SectorState {\n");
+    for transition in TRANSITIONS {
+        writeln!(
+            dot,
+            "    {:?} -> {:?} [label=\"{}\"];",
+            transition.from, transition.to, transition.label
+        )
+        .expect("write to string never fails");
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// The transition table as a Mermaid state diagram.
+pub fn transitions_mermaid() -> String {
+    let mut mermaid = String::from("stateDiagram-v2\n    [*] --> Empty\n");
+    for transition in TRANSITIONS {
+        writeln!(
+            mermaid,
+            "    {:?} --> {:?}: {}",
+            transition.from, transition.to, transition.label
+        )
+        .expect("write to string never fails");
+    }
+    mermaid
+}